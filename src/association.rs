@@ -0,0 +1,434 @@
+use detection::{log_sum_exp, Observation};
+use glm::Vec2;
+use measurement::{Measurement, MeasurementKind};
+use std::f64;
+use track::Track;
+use tracker::AnimalTracker;
+
+// Stand-in for an impossible assignment, finite so the Hungarian algorithm still runs
+const FORBIDDEN: f64 = 1e9;
+// Most joint events JPDA enumerates for one cluster of trackers before falling back
+// to global nearest neighbour for it
+const MAX_JPDA_EVENTS: f64 = 100_000.0;
+
+// A drone's detections from one measurement, without knowing which animal each one
+// came from
+#[derive(Clone, Debug)]
+pub struct Scan {
+    pub time: f64,
+    pub drone: Vec2,
+    pub sensor: MeasurementKind,
+    pub detections: Vec<Measurement>,
+}
+
+// How anonymous detections are shared between trackers. `gate` is the log density
+// of a detection being clutter, a detection is only given to a tracker that explains
+// it better than that.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub enum Association {
+    // Global nearest neighbour, each tracker takes the detection from the most likely
+    // joint assignment
+    NearestNeighbour { gate: f32 },
+    // Joint probabilistic data association, each tracker is updated by every
+    // detection weighted by the probability it came from that tracker's animal
+    Jpda { gate: f32 },
+}
+
+// Log likelihoods of each tracker hearing each detection, and of it missing its
+// animal
+struct Scores {
+    detected: Vec<Vec<f32>>,
+    missed: Vec<f32>,
+}
+
+impl Scores {
+    fn new(scan: &Scan, trackers: &[(AnimalTracker, Track)]) -> Scores {
+        let observation = |measurement: Option<&Measurement>| {
+            Observation::new(
+                scan.time,
+                scan.drone,
+                scan.sensor.clone(),
+                measurement.cloned(),
+            )
+        };
+        let missed = observation(None);
+        let detected: Vec<Observation> = scan
+            .detections
+            .iter()
+            .map(|x| observation(Some(x)))
+            .collect();
+
+        Scores {
+            detected: trackers
+                .iter()
+                .map(|(tracker, _)| detected.iter().map(|x| tracker.log_predictive(x)).collect())
+                .collect(),
+            missed: trackers
+                .iter()
+                .map(|(tracker, _)| tracker.log_predictive(&missed))
+                .collect(),
+        }
+    }
+}
+
+impl Association {
+    // Splits a scan into an observation for each tracker, in tracker order, and the
+    // indices of detections more likely to have come from none of them
+    pub fn associate(
+        &self,
+        scan: &Scan,
+        trackers: &[(AnimalTracker, Track)],
+    ) -> (Vec<Observation>, Vec<usize>) {
+        let scores = Scores::new(scan, trackers);
+        let hypotheses = match *self {
+            Association::NearestNeighbour { gate } => nearest_neighbour(&scores, gate),
+            Association::Jpda { gate } => jpda(&scores, gate),
+        };
+
+        let mut associated = vec![0.0; scan.detections.len()];
+        for &(probability, index) in hypotheses.iter().flat_map(|x| x.iter()) {
+            if let Some(j) = index {
+                associated[j] += probability;
+            }
+        }
+        let unassociated = (0..scan.detections.len())
+            .filter(|&j| associated[j] < 0.5)
+            .collect();
+
+        let observations = hypotheses
+            .into_iter()
+            .map(|hypotheses| Observation {
+                time: scan.time,
+                drone: scan.drone,
+                sensor: scan.sensor.clone(),
+                hypotheses: hypotheses
+                    .into_iter()
+                    .map(|(probability, index)| {
+                        (probability, index.map(|j| scan.detections[j].clone()))
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        (observations, unassociated)
+    }
+}
+
+// Each tracker's hypotheses as (probability, detection index), None for a miss
+type Hypotheses = Vec<Vec<(f32, Option<usize>)>>;
+
+fn cost(log_likelihood: f32) -> f64 {
+    if log_likelihood.is_finite() {
+        (-log_likelihood as f64).min(FORBIDDEN)
+    } else {
+        FORBIDDEN
+    }
+}
+
+fn nearest_neighbour(scores: &Scores, gate: f32) -> Hypotheses {
+    let trackers = scores.missed.len();
+    let detections = scores.detected.first().map_or(0, |x| x.len());
+    let size = trackers + detections;
+
+    // Trackers and then clutter along the rows, detections and then misses along the
+    // columns
+    let mut costs = vec![vec![FORBIDDEN; size]; size];
+    for (t, row) in costs[..trackers].iter_mut().enumerate() {
+        for (entry, &score) in row.iter_mut().zip(scores.detected[t].iter()) {
+            if score >= gate {
+                *entry = cost(score);
+            }
+        }
+        row[detections + t] = cost(scores.missed[t]);
+    }
+    for (j, row) in costs[trackers..].iter_mut().enumerate() {
+        row[j] = cost(gate);
+        for missed in row[detections..].iter_mut() {
+            *missed = 0.0;
+        }
+    }
+
+    let assignment = hungarian(&costs);
+    (0..trackers)
+        .map(|t| {
+            let j = assignment[t];
+            if j < detections && costs[t][j] < FORBIDDEN {
+                vec![(1.0, Some(j))]
+            } else {
+                vec![(1.0, None)]
+            }
+        })
+        .collect()
+}
+
+// Minimum cost assignment of rows to columns of a square matrix, using the
+// Hungarian algorithm with potentials. Returns the column of each row.
+fn hungarian(costs: &[Vec<f64>]) -> Vec<usize> {
+    let n = costs.len();
+    // One based, with row and column 0 as the unassigned sentinel
+    let mut u = vec![0.0; n + 1];
+    let mut v = vec![0.0; n + 1];
+    let mut row_of = vec![0; n + 1];
+    let mut way = vec![0; n + 1];
+
+    for row in 1..n + 1 {
+        row_of[0] = row;
+        let mut column = 0;
+        let mut min_slack = vec![f64::INFINITY; n + 1];
+        let mut used = vec![false; n + 1];
+
+        loop {
+            used[column] = true;
+            let current = row_of[column];
+            let mut delta = f64::INFINITY;
+            let mut next = 0;
+            for j in 1..n + 1 {
+                if used[j] {
+                    continue;
+                }
+                let slack = costs[current - 1][j - 1] - u[current] - v[j];
+                if slack < min_slack[j] {
+                    min_slack[j] = slack;
+                    way[j] = column;
+                }
+                if min_slack[j] < delta {
+                    delta = min_slack[j];
+                    next = j;
+                }
+            }
+            for j in 0..n + 1 {
+                if used[j] {
+                    u[row_of[j]] += delta;
+                    v[j] -= delta;
+                } else {
+                    min_slack[j] -= delta;
+                }
+            }
+
+            column = next;
+            if row_of[column] == 0 {
+                break;
+            }
+        }
+
+        // Flip the augmenting path
+        loop {
+            let previous = way[column];
+            row_of[column] = row_of[previous];
+            column = previous;
+            if column == 0 {
+                break;
+            }
+        }
+    }
+
+    let mut assignment = vec![0; n];
+    for j in 1..n + 1 {
+        if row_of[j] > 0 {
+            assignment[row_of[j] - 1] = j - 1;
+        }
+    }
+
+    assignment
+}
+
+// Splits the trackers into clusters that share gated detections, which are
+// independent of each other, and runs JPDA on each
+fn jpda(scores: &Scores, gate: f32) -> Hypotheses {
+    let trackers = scores.missed.len();
+    let detections = scores.detected.first().map_or(0, |x| x.len());
+    let gated = |t: usize, j: usize| scores.detected[t][j] >= gate;
+
+    // Union-find over trackers, joined through the detections they both gate
+    let mut parent: Vec<usize> = (0..trackers).collect();
+    for j in 0..detections {
+        let mut first = None;
+        for t in (0..trackers).filter(|&t| gated(t, j)) {
+            match first {
+                None => first = Some(t),
+                Some(first) => {
+                    let (a, b) = (root(&mut parent, first), root(&mut parent, t));
+                    parent[a] = b;
+                }
+            }
+        }
+    }
+
+    let mut hypotheses = vec![vec![]; trackers];
+    let mut done = vec![false; trackers];
+    for t in 0..trackers {
+        if done[t] {
+            continue;
+        }
+        let cluster_root = root(&mut parent, t);
+        let members: Vec<usize> = (t..trackers)
+            .filter(|&u| root(&mut parent, u) == cluster_root)
+            .collect();
+        let shared: Vec<usize> = (0..detections)
+            .filter(|&j| members.iter().any(|&u| gated(u, j)))
+            .collect();
+        let cluster = Scores {
+            detected: members
+                .iter()
+                .map(|&u| shared.iter().map(|&j| scores.detected[u][j]).collect())
+                .collect(),
+            missed: members.iter().map(|&u| scores.missed[u]).collect(),
+        };
+
+        // Each tracker can miss or take any detection it gates
+        let events: f64 = members
+            .iter()
+            .map(|&u| 1.0 + shared.iter().filter(|&&j| gated(u, j)).count() as f64)
+            .product();
+        let cluster_hypotheses = if events > MAX_JPDA_EVENTS {
+            nearest_neighbour(&cluster, gate)
+        } else {
+            jpda_cluster(&cluster, gate)
+        };
+
+        for (&u, choices) in members.iter().zip(cluster_hypotheses) {
+            done[u] = true;
+            hypotheses[u] = choices
+                .into_iter()
+                .map(|(probability, index)| (probability, index.map(|j| shared[j])))
+                .collect();
+        }
+    }
+
+    hypotheses
+}
+
+// Representative of tracker `t`'s cluster, halving the path on the way up
+fn root(parent: &mut [usize], mut t: usize) -> usize {
+    while parent[t] != t {
+        parent[t] = parent[parent[t]];
+        t = parent[t];
+    }
+    t
+}
+
+// Enumerates every joint event of trackers hearing distinct gated detections or
+// missing, so the cost grows quickly with the number of trackers sharing detections
+fn jpda_cluster(scores: &Scores, gate: f32) -> Hypotheses {
+    let trackers = scores.missed.len();
+    let detections = scores.detected.first().map_or(0, |x| x.len());
+
+    // Log weight of each event, summed per tracker and choice, with the miss last
+    let mut marginals = vec![vec![vec![]; detections + 1]; trackers];
+    let mut choices = vec![detections; trackers];
+    let mut used = vec![false; detections];
+    enumerate_events(
+        scores,
+        gate,
+        0,
+        0.0,
+        &mut choices,
+        &mut used,
+        &mut marginals,
+    );
+
+    marginals
+        .into_iter()
+        .map(|choices| {
+            let logs: Vec<f32> = choices
+                .iter()
+                .map(|events| log_sum_exp(events.iter().cloned()))
+                .collect();
+            let total = log_sum_exp(logs.iter().cloned());
+            if !total.is_finite() {
+                return vec![(1.0, None)];
+            }
+
+            logs.iter()
+                .enumerate()
+                .map(|(j, log)| ((log - total).exp(), j))
+                .filter(|&(probability, _)| probability > 0.0)
+                .map(|(probability, j)| {
+                    let index = if j < detections { Some(j) } else { None };
+                    (probability, index)
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn enumerate_events(
+    scores: &Scores,
+    gate: f32,
+    tracker: usize,
+    log_weight: f32,
+    choices: &mut [usize],
+    used: &mut [bool],
+    marginals: &mut [Vec<Vec<f32>>],
+) {
+    let detections = used.len();
+    if tracker == choices.len() {
+        // Whatever no tracker heard is clutter
+        let clutter = used.iter().filter(|&&x| !x).count() as f32 * gate;
+        let log_weight = log_weight + clutter;
+        if log_weight.is_finite() {
+            for (t, &j) in choices.iter().enumerate() {
+                marginals[t][j].push(log_weight);
+            }
+        }
+        return;
+    }
+
+    choices[tracker] = detections;
+    let missed = log_weight + scores.missed[tracker];
+    enumerate_events(scores, gate, tracker + 1, missed, choices, used, marginals);
+
+    for j in 0..detections {
+        let score = scores.detected[tracker][j];
+        if used[j] || score < gate {
+            continue;
+        }
+        used[j] = true;
+        choices[tracker] = j;
+        let heard = log_weight + score;
+        enumerate_events(scores, gate, tracker + 1, heard, choices, used, marginals);
+        used[j] = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hungarian_finds_the_cheapest_assignment() {
+        let costs = vec![
+            vec![4.0, 1.0, 3.0],
+            vec![2.0, 0.0, 5.0],
+            vec![3.0, 2.0, 2.0],
+        ];
+
+        // 1 + 2 + 2 beats every other permutation
+        assert_eq!(hungarian(&costs), vec![1, 0, 2]);
+    }
+
+    #[test]
+    fn jpda_marginals_sum_to_one_per_tracker() {
+        let scores = Scores {
+            detected: vec![vec![-1.0, -3.0], vec![-2.5, -0.5]],
+            missed: vec![-4.0, -4.5],
+        };
+
+        let hypotheses = jpda(&scores, -6.0);
+
+        assert_eq!(hypotheses.len(), 2);
+        for choices in hypotheses.iter() {
+            let total: f32 = choices.iter().map(|x| x.0).sum();
+            assert!((total - 1.0).abs() < 1e-5, "marginals sum to {}", total);
+        }
+        // Each tracker most likely heard the detection it explains best
+        let best = |choices: &Vec<(f32, Option<usize>)>| {
+            choices
+                .iter()
+                .fold((0.0, None), |best, x| if x.0 > best.0 { *x } else { best })
+                .1
+        };
+        assert_eq!(best(&hypotheses[0]), Some(0));
+        assert_eq!(best(&hypotheses[1]), Some(1));
+    }
+}
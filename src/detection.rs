@@ -0,0 +1,280 @@
+use glm::*;
+use measurement::{Measurement, MeasurementKind, MeasurementModel};
+use rand::Rng;
+use std::f32;
+use std::f32::consts::PI;
+
+// Smallest chance of missing an animal the trackers allow for, so a miss under a
+// certain detection probability lowers weights instead of ruling every particle out
+const MIN_MISS_PROBABILITY: f32 = 1e-3;
+
+// Where clutter lands in measurement space. Clutter is uniform over the ranges the
+// receiver can report and over all bearings.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Clutter {
+    pub max_range: f32,
+    // Bounds of clutter signal strength in dBm
+    pub min_power: f32,
+    pub max_power: f32,
+}
+
+impl Default for Clutter {
+    fn default() -> Clutter {
+        Clutter {
+            max_range: 20.0,
+            min_power: -100.0,
+            max_power: -40.0,
+        }
+    }
+}
+
+// How the detection probability falls off with distance from the drone
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub enum DetectionCurve {
+    // The same probability out to the maximum range
+    #[default]
+    Constant,
+    // Falls to zero at the maximum range, constant when there is none
+    Linear,
+    // Halves at `midpoint`, falling faster the larger `steepness` is
+    Logistic {
+        midpoint: f32,
+        steepness: f32,
+    },
+}
+
+// What one drone made of an animal at `time` seconds into the simulation
+#[derive(Clone, Debug)]
+pub struct Observation {
+    pub time: f64,
+    pub drone: Vec2,
+    pub sensor: MeasurementKind,
+    // What the drone may have heard from the animal, each with its probability. A
+    // single certain entry unless data association was unsure. None is a miss.
+    pub hypotheses: Vec<(f32, Option<Measurement>)>,
+}
+
+impl Observation {
+    pub fn new(
+        time: f64,
+        drone: Vec2,
+        sensor: MeasurementKind,
+        measurement: Option<Measurement>,
+    ) -> Observation {
+        Observation {
+            time,
+            drone,
+            sensor,
+            hypotheses: vec![(1.0, measurement)],
+        }
+    }
+
+    // The most probable thing heard, None if a miss is most probable
+    pub fn measurement(&self) -> Option<&Measurement> {
+        self.hypotheses
+            .iter()
+            .fold(
+                None,
+                |best: Option<&(f32, Option<Measurement>)>, x| match best {
+                    Some(best) if best.0 >= x.0 => Some(best),
+                    _ => Some(x),
+                },
+            )
+            .and_then(|x| x.1.as_ref())
+    }
+}
+
+// How reliably the receiver hears an animal
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Detection {
+    // Chance the animal is heard on each measurement when right below the drone
+    pub probability: f32,
+    // Animals further away than this are never heard
+    #[serde(default)]
+    pub max_range: Option<f32>,
+    #[serde(default)]
+    pub curve: DetectionCurve,
+    // Chance a detection is clutter, such as a multipath spike, rather than the animal
+    pub outlier_rate: f32,
+    #[serde(default)]
+    pub clutter: Clutter,
+}
+
+impl Default for Detection {
+    fn default() -> Detection {
+        Detection {
+            probability: 1.0,
+            max_range: None,
+            curve: DetectionCurve::Constant,
+            outlier_rate: 0.0,
+            clutter: Clutter::default(),
+        }
+    }
+}
+
+impl Clutter {
+    // Draws clutter of the same kind as `measurement`
+    pub fn sample<R: Rng + ?Sized>(&self, measurement: &Measurement, rng: &mut R) -> Measurement {
+        let range = rng.gen::<f32>() * self.max_range;
+        let bearing = (rng.gen::<f32>() * 2.0 - 1.0) * PI;
+        let power = self.min_power + rng.gen::<f32>() * (self.max_power - self.min_power);
+
+        match measurement {
+            Measurement::Range(_) => Measurement::Range(range),
+            Measurement::Bearing(_) => Measurement::Bearing(bearing),
+            Measurement::RangeBearing { .. } => Measurement::RangeBearing { range, bearing },
+            Measurement::Rssi(_) => Measurement::Rssi(power),
+            Measurement::Tdoa(differences) => Measurement::Tdoa(
+                differences
+                    .iter()
+                    .map(|_| (rng.gen::<f32>() * 2.0 - 1.0) * self.max_range)
+                    .collect(),
+            ),
+        }
+    }
+
+    pub fn log_density(&self, measurement: &Measurement) -> f32 {
+        let range = -self.max_range.ln();
+        let bearing = -(2.0 * PI).ln();
+
+        match measurement {
+            Measurement::Range(_) => range,
+            Measurement::Bearing(_) => bearing,
+            Measurement::RangeBearing { .. } => range + bearing,
+            Measurement::Rssi(_) => -(self.max_power - self.min_power).ln(),
+            // Differences are spread over twice the range, either receiver may be nearer
+            Measurement::Tdoa(differences) => {
+                -(2.0 * self.max_range).ln() * differences.len() as f32
+            }
+        }
+    }
+}
+
+impl Detection {
+    // Chance of hearing an animal `distance` away from the drone
+    pub fn probability_at(&self, distance: f32) -> f32 {
+        if let Some(max_range) = self.max_range {
+            if distance > max_range {
+                return 0.0;
+            }
+        }
+
+        let falloff = match (self.curve, self.max_range) {
+            (DetectionCurve::Constant, _) | (DetectionCurve::Linear, None) => 1.0,
+            (DetectionCurve::Linear, Some(max_range)) => 1.0 - distance / max_range,
+            (
+                DetectionCurve::Logistic {
+                    midpoint,
+                    steepness,
+                },
+                _,
+            ) => 1.0 / (1.0 + (steepness * (distance - midpoint)).exp()),
+        };
+
+        self.probability * falloff
+    }
+
+    // Log likelihood of not hearing an animal at `particle`
+    pub fn log_missed(&self, drone: Vec2, particle: Vec2) -> f32 {
+        let probability = self.probability_at(distance(&drone, &particle));
+        (1.0 - probability).max(MIN_MISS_PROBABILITY).ln()
+    }
+
+    // Simulates the receiver listening for an animal. None when the animal is missed.
+    pub fn observe<R: Rng + ?Sized>(
+        &self,
+        sensor: &MeasurementKind,
+        drone: Vec2,
+        animal: Vec2,
+        rng: &mut R,
+    ) -> Option<Measurement> {
+        if rng.gen::<f32>() >= self.probability_at(distance(&drone, &animal)) {
+            return None;
+        }
+
+        let measurement = sensor.generate(drone, animal, rng);
+        if rng.gen::<f32>() < self.outlier_rate {
+            Some(self.clutter.sample(&measurement, rng))
+        } else {
+            Some(measurement)
+        }
+    }
+
+    // Log likelihood of a detection that may be clutter, a mixture of the sensor's
+    // likelihood and the clutter density, scaled by the chance of hearing the animal
+    // at `particle` at all
+    pub fn log_likelihood(
+        &self,
+        sensor: &MeasurementKind,
+        drone: Vec2,
+        particle: Vec2,
+        measurement: &Measurement,
+    ) -> f32 {
+        let log_detected = self.probability_at(distance(&drone, &particle)).ln();
+        let log_sensor = sensor.log_likelihood(drone, particle, measurement);
+        if self.outlier_rate <= 0.0 {
+            return log_detected + log_sensor;
+        }
+
+        let a = (1.0 - self.outlier_rate).ln() + log_sensor;
+        let b = self.outlier_rate.ln() + self.clutter.log_density(measurement);
+        let max = a.max(b);
+        if !max.is_finite() {
+            return max;
+        }
+        log_detected + max + ((a - max).exp() + (b - max).exp()).ln()
+    }
+
+    // Log likelihood of an observation, heard or not, if the animal is at `particle`.
+    // Uncertain observations mix the likelihood of each hypothesis.
+    pub fn log_observation(&self, observation: &Observation, particle: Vec2) -> f32 {
+        let (sensor, drone) = (&observation.sensor, observation.drone);
+        let log_hypothesis = |&(probability, ref measurement): &(f32, Option<Measurement>)| {
+            let log_likelihood = match *measurement {
+                Some(ref measurement) => self.log_likelihood(sensor, drone, particle, measurement),
+                None => self.log_missed(drone, particle),
+            };
+            probability.ln() + log_likelihood
+        };
+
+        if observation.hypotheses.len() == 1 {
+            return log_hypothesis(&observation.hypotheses[0]);
+        }
+        log_sum_exp(observation.hypotheses.iter().map(log_hypothesis))
+    }
+}
+
+pub fn log_sum_exp(values: impl Iterator<Item = f32> + Clone) -> f32 {
+    let max = values.clone().fold(f32::NEG_INFINITY, f32::max);
+    if !max.is_finite() {
+        return max;
+    }
+    max + values.map(|x| (x - max).exp()).sum::<f32>().ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use filter::particle::KinematicState;
+    use filter::Filter;
+    use measurement::RangeModel;
+    use random;
+
+    #[test]
+    fn miss_with_default_detection_keeps_weights_finite() {
+        let detection = Detection::default();
+        let sensor = MeasurementKind::Range(RangeModel {
+            stddev: 0.5,
+            likelihood: Default::default(),
+        });
+        let observation = Observation::new(0.0, vec2(1.0, 2.0), sensor, None);
+        let mut filter: Filter<KinematicState> =
+            Filter::new_start_box(100, -10.0..10.0, -10.0..10.0, random::stream(3, 0));
+
+        filter.reweight(|particle| detection.log_observation(&observation, particle.position));
+
+        assert!(filter.normalize_weights().is_ok());
+        assert!(filter.log_weights.iter().all(|x| x.is_finite()));
+        assert!(filter.weights.iter().all(|x| x.is_finite()));
+    }
+}
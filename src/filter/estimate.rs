@@ -0,0 +1,129 @@
+use glm::*;
+use std::collections::HashMap;
+use std::f32;
+
+// Chi-squared quantile with two degrees of freedom, -2 ln(1 - p)
+fn chi_squared_2(probability: f32) -> f32 {
+    -2.0 * (1.0 - probability).ln()
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Ellipse {
+    pub center: Vec2,
+    // Semi-major and semi-minor axis lengths
+    pub axes: Vec2,
+    // Angle of the major axis from the x axis, in radians
+    pub angle: f32,
+}
+
+impl Ellipse {
+    // Region of a gaussian with the given mean and covariance holding `probability`
+    // of its mass
+    pub fn from_covariance(center: Vec2, covariance: &Mat2, probability: f32) -> Ellipse {
+        let xx = covariance[(0, 0)];
+        let xy = covariance[(0, 1)];
+        let yy = covariance[(1, 1)];
+
+        let mean = 0.5 * (xx + yy);
+        let spread = (0.25 * (xx - yy) * (xx - yy) + xy * xy).sqrt();
+        let major = (mean + spread).max(0.0);
+        let minor = (mean - spread).max(0.0);
+        let angle = 0.5 * (2.0 * xy).atan2(xx - yy);
+
+        let scale = chi_squared_2(probability);
+        Ellipse {
+            center,
+            axes: vec2((major * scale).sqrt(), (minor * scale).sqrt()),
+            angle,
+        }
+    }
+
+    pub fn contains(&self, point: Vec2) -> bool {
+        let offset = point - self.center;
+        let (sin, cos) = self.angle.sin_cos();
+        let u = (offset.x * cos + offset.y * sin) / self.axes.x;
+        let v = (-offset.x * sin + offset.y * cos) / self.axes.y;
+        u * u + v * v <= 1.0
+    }
+
+    pub fn area(&self) -> f32 {
+        f32::consts::PI * self.axes.x * self.axes.y
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Estimate<T> {
+    pub mean: T,
+    pub covariance: Mat2,
+    // Highest weighted particle
+    pub map: T,
+    // Centre of the densest cluster of particles
+    pub mode: Vec2,
+    // 95% credible region of the position
+    pub credible: Ellipse,
+    pub effective_sample_size: f32,
+}
+
+pub fn covariance(positions: &[Vec2], weights: &[f32], mean: Vec2) -> Mat2 {
+    let (mut xx, mut xy, mut yy) = (0.0, 0.0, 0.0);
+    for (position, weight) in positions.iter().zip(weights.iter()) {
+        let offset = position - mean;
+        xx += weight * offset.x * offset.x;
+        xy += weight * offset.x * offset.y;
+        yy += weight * offset.y * offset.y;
+    }
+
+    mat2(xx, xy, xy, yy)
+}
+
+// Bins positions into square cells of `cell_size` and returns the weighted mean of
+// the heaviest 3x3 block of cells
+pub fn cluster_mode(positions: &[Vec2], weights: &[f32], cell_size: f32) -> Vec2 {
+    let cell = |p: &Vec2| {
+        (
+            (p.x / cell_size).floor() as i32,
+            (p.y / cell_size).floor() as i32,
+        )
+    };
+
+    let mut cells: HashMap<(i32, i32), f32> = HashMap::new();
+    for (position, weight) in positions.iter().zip(weights.iter()) {
+        *cells.entry(cell(position)).or_insert(0.0) += weight;
+    }
+
+    let block_weight = |&(x, y): &(i32, i32)| {
+        let mut sum = 0.0;
+        for i in -1..2 {
+            for j in -1..2 {
+                sum += cells.get(&(x + i, y + j)).cloned().unwrap_or(0.0);
+            }
+        }
+        sum
+    };
+
+    let mut best = (0, 0);
+    let mut best_weight = f32::NEG_INFINITY;
+    for key in cells.keys() {
+        let weight = block_weight(key);
+        if weight > best_weight {
+            best = *key;
+            best_weight = weight;
+        }
+    }
+
+    let mut sum = vec2(0.0, 0.0);
+    let mut total = 0.0;
+    for (position, weight) in positions.iter().zip(weights.iter()) {
+        let (x, y) = cell(position);
+        if (x - best.0).abs() <= 1 && (y - best.1).abs() <= 1 {
+            sum += position * *weight;
+            total += weight;
+        }
+    }
+
+    if total > 0.0 {
+        sum / total
+    } else {
+        sum
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::{HashSet, VecDeque};
+
+#[derive(Clone, Debug)]
+struct Generation<T> {
+    // Particles before the resample
+    parents: Vec<T>,
+    // For each particle after the resample, the index of its parent. None for particles
+    // injected since, which start a new lineage.
+    ancestors: Vec<Option<usize>>,
+}
+
+// Ancestry of the particles over the last `depth` resamples. Particles keep their
+// index between resamples, so index `i` of one generation is the particle that the
+// next generation's ancestors refer to.
+#[derive(Clone, Debug)]
+pub struct Genealogy<T> {
+    generations: VecDeque<Generation<T>>,
+    depth: usize,
+}
+
+impl<T: Copy> Genealogy<T> {
+    pub fn new(depth: usize) -> Genealogy<T> {
+        Genealogy {
+            generations: VecDeque::with_capacity(depth),
+            depth,
+        }
+    }
+
+    pub fn record(&mut self, parents: &[T], ancestors: &[usize]) {
+        if self.depth == 0 {
+            return;
+        }
+        // The oldest generation's buffers are reused once the history is full
+        let mut generation = if self.generations.len() == self.depth {
+            self.generations.pop_front().unwrap()
+        } else {
+            Generation {
+                parents: Vec::with_capacity(parents.len()),
+                ancestors: Vec::with_capacity(ancestors.len()),
+            }
+        };
+        generation.parents.clear();
+        generation.parents.extend_from_slice(parents);
+        generation.ancestors.clear();
+        generation
+            .ancestors
+            .extend(ancestors.iter().map(|&x| Some(x)));
+
+        self.generations.push_back(generation);
+    }
+
+    // Cuts the current particles at `indices` off from their ancestors, as they were
+    // replaced by new draws
+    pub fn mark_roots(&mut self, indices: &[usize]) {
+        if let Some(generation) = self.generations.back_mut() {
+            for &index in indices {
+                if let Some(ancestor) = generation.ancestors.get_mut(index) {
+                    *ancestor = None;
+                }
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.generations.clear();
+    }
+
+    pub fn generations(&self) -> usize {
+        self.generations.len()
+    }
+
+    // Index of particle `index`'s ancestor `lag` resamples ago, where a lag of 0 is
+    // the particle itself
+    pub fn ancestor(&self, index: usize, lag: usize) -> Option<usize> {
+        if lag > self.generations.len() {
+            return None;
+        }
+
+        let mut index = index;
+        for generation in self.generations.iter().rev().take(lag) {
+            index = (*generation.ancestors.get(index)?)?;
+        }
+
+        Some(index)
+    }
+
+    // States the particle descends from, newest first
+    pub fn lineage(&self, index: usize) -> Vec<T> {
+        let mut lineage = Vec::with_capacity(self.generations.len());
+        let mut index = index;
+
+        for generation in self.generations.iter().rev() {
+            index = match generation.ancestors.get(index) {
+                Some(&Some(index)) => index,
+                _ => break,
+            };
+            lineage.push(generation.parents[index]);
+        }
+
+        lineage
+    }
+
+    // Distinct ancestors the `particles` current particles have `lag` resamples back.
+    // Dropping towards one shows path degeneracy.
+    pub fn unique_ancestors(&self, particles: usize, lag: usize) -> usize {
+        let ancestors: HashSet<usize> = (0..particles)
+            .filter_map(|i| self.ancestor(i, lag))
+            .collect();
+
+        ancestors.len()
+    }
+
+    // Resamples back until every current particle shares a single ancestor, if that
+    // happens within the stored history
+    pub fn coalescence_time(&self, particles: usize) -> Option<usize> {
+        (1..self.generations.len() + 1).find(|&lag| self.unique_ancestors(particles, lag) == 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two resamples of four particles. Every particle goes back to particle 0 two
+    // resamples ago.
+    fn genealogy() -> Genealogy<i32> {
+        let mut genealogy = Genealogy::new(3);
+        genealogy.record(&[10, 11, 12, 13], &[0, 0, 1, 0]);
+        genealogy.record(&[20, 21, 22, 23], &[1, 1, 0, 3]);
+        genealogy
+    }
+
+    #[test]
+    fn ancestry_follows_each_resample_back() {
+        let genealogy = genealogy();
+
+        assert_eq!(genealogy.generations(), 2);
+        assert_eq!(genealogy.ancestor(3, 0), Some(3));
+        assert_eq!(genealogy.ancestor(3, 1), Some(3));
+        assert_eq!(genealogy.ancestor(3, 2), Some(0));
+        assert_eq!(genealogy.ancestor(3, 3), None);
+        assert_eq!(genealogy.lineage(3), vec![23, 10]);
+        assert_eq!(genealogy.unique_ancestors(4, 1), 3);
+        assert_eq!(genealogy.unique_ancestors(4, 2), 1);
+        assert_eq!(genealogy.coalescence_time(4), Some(2));
+    }
+
+    #[test]
+    fn roots_have_no_ancestors() {
+        let mut genealogy = genealogy();
+        genealogy.mark_roots(&[0, 1]);
+
+        assert_eq!(genealogy.ancestor(0, 1), None);
+        assert!(genealogy.lineage(1).is_empty());
+        assert_eq!(genealogy.unique_ancestors(4, 1), 2);
+        assert_eq!(genealogy.unique_ancestors(4, 2), 1);
+    }
+}
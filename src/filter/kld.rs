@@ -0,0 +1,71 @@
+use glm::Vec2;
+use statrs::function::erf;
+use std::collections::HashSet;
+use std::f64::consts::SQRT_2;
+
+// Bounds for KLD-sampling. The particle count is chosen so that, with probability
+// 1 - delta, the KL divergence between the sampled and true posterior stays below
+// epsilon, measured over a grid of `bin_size` cells.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct KldConfig {
+    pub min_particles: usize,
+    pub max_particles: usize,
+    pub bin_size: f32,
+    pub epsilon: f32,
+    pub delta: f32,
+}
+
+impl Default for KldConfig {
+    fn default() -> KldConfig {
+        KldConfig {
+            min_particles: 100,
+            max_particles: 2000,
+            bin_size: 0.5,
+            epsilon: 0.05,
+            delta: 0.01,
+        }
+    }
+}
+
+impl KldConfig {
+    // Particles needed once samples occupy `bins` bins, using the Wilson-Hilferty
+    // approximation of the chi-squared quantile
+    pub fn required_particles(&self, bins: usize) -> usize {
+        if bins < 2 {
+            return self.min_particles;
+        }
+
+        let z = SQRT_2 * erf::erf_inv(1.0 - 2.0 * self.delta as f64);
+        let k = (bins - 1) as f64;
+        let a = 2.0 / (9.0 * k);
+        let n = k / (2.0 * self.epsilon as f64) * (1.0 - a + a.sqrt() * z).powi(3);
+
+        (n.ceil() as usize)
+            .max(self.min_particles)
+            .min(self.max_particles)
+    }
+}
+
+pub struct KldBins {
+    size: f32,
+    occupied: HashSet<(i32, i32)>,
+}
+
+impl KldBins {
+    pub fn new(size: f32) -> KldBins {
+        KldBins {
+            size,
+            occupied: HashSet::new(),
+        }
+    }
+
+    pub fn insert(&mut self, position: Vec2) {
+        let x = (position.x / self.size).floor() as i32;
+        let y = (position.y / self.size).floor() as i32;
+        self.occupied.insert((x, y));
+    }
+
+    pub fn len(&self) -> usize {
+        self.occupied.len()
+    }
+}
@@ -0,0 +1,17 @@
+// Metropolis-Hastings move applied after resampling. Each particle takes
+// `iterations` gaussian random walk proposals of `stddev`, so duplicated particles
+// spread out while still following the posterior.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct McmcConfig {
+    pub iterations: usize,
+    pub stddev: f32,
+}
+
+impl Default for McmcConfig {
+    fn default() -> McmcConfig {
+        McmcConfig {
+            iterations: 2,
+            stddev: 0.1,
+        }
+    }
+}
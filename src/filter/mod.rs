@@ -0,0 +1,609 @@
+use glm::*;
+use of::OrderedFloat;
+use rand::{seq, Rng};
+use random::{self, SimRng};
+use std::borrow::Cow;
+use std::error::Error;
+use std::f32;
+use std::fmt;
+use std::mem::{self, swap};
+use std::ops::Range;
+
+pub mod estimate;
+pub mod genealogy;
+pub mod kld;
+pub mod mcmc;
+pub mod particle;
+pub mod regularize;
+pub mod resample;
+pub mod smoother;
+
+use self::estimate::{Ellipse, Estimate};
+use self::genealogy::Genealogy;
+use self::kld::{KldBins, KldConfig};
+use self::mcmc::McmcConfig;
+use self::particle::ParticleState;
+use self::regularize::Regularization;
+use self::resample::{ResamplePolicy, Resampler, ResamplingScheme};
+
+// Returned when every particle has a zero (or NaN) likelihood, so the weights
+// can't be normalised
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DegenerateWeights;
+
+impl fmt::Display for DegenerateWeights {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "all particle weights are zero")
+    }
+}
+
+impl Error for DegenerateWeights {}
+
+#[derive(Clone, Debug)]
+pub struct Filter<T: Copy> {
+    pub particles: Vec<T>,
+    old_particles: Vec<T>,
+    indices: Vec<usize>,
+    // Each particle before its last transition, if it has one
+    previous: Vec<Option<T>>,
+
+    pub log_weights: Vec<f32>,
+    // First stage log likelihoods of the auxiliary filter, per resampled particle
+    first_stage: Vec<f32>,
+    // Normalised linear weights, kept in sync with `log_weights` by `normalize_weights`
+    pub weights: Vec<f32>,
+    pub resampler: ResamplingScheme,
+    pub policy: ResamplePolicy,
+    pub kld: Option<KldConfig>,
+    pub regularization: Option<Regularization>,
+    // Records ancestor indices at each resample when set
+    pub genealogy: Option<Genealogy<T>>,
+    pub rng: SimRng,
+}
+
+impl<T: Copy> Filter<T> {
+    pub fn new() -> Filter<T> {
+        Filter {
+            particles: vec![],
+            old_particles: vec![],
+            indices: vec![],
+            previous: vec![],
+
+            log_weights: vec![],
+            first_stage: vec![],
+            weights: vec![],
+            resampler: ResamplingScheme::default(),
+            policy: ResamplePolicy::default(),
+            kld: None,
+            regularization: None,
+            genealogy: None,
+            rng: random::from_entropy(),
+        }
+    }
+
+    pub fn get_particles(&self) -> &[T] {
+        self.particles.as_slice()
+    }
+
+    pub fn with_capacity(cap: usize) -> Filter<T> {
+        Filter {
+            particles: Vec::with_capacity(cap),
+            old_particles: Vec::with_capacity(cap),
+            indices: Vec::with_capacity(cap),
+            previous: Vec::with_capacity(cap),
+            log_weights: Vec::with_capacity(cap),
+            first_stage: Vec::with_capacity(cap),
+            weights: Vec::with_capacity(cap),
+            resampler: ResamplingScheme::default(),
+            policy: ResamplePolicy::default(),
+            kld: None,
+            regularization: None,
+            genealogy: None,
+            rng: random::from_entropy(),
+        }
+    }
+
+    pub fn populate_filter<F>(&mut self, func: F)
+    where
+        F: FnOnce(&mut Vec<T>),
+    {
+        func(&mut self.particles);
+    }
+
+    pub fn set_resampler(&mut self, resampler: ResamplingScheme) {
+        self.resampler = resampler;
+    }
+
+    pub fn set_policy(&mut self, policy: ResamplePolicy) {
+        self.policy = policy;
+    }
+
+    pub fn set_kld(&mut self, kld: Option<KldConfig>) {
+        self.kld = kld;
+    }
+
+    pub fn set_regularization(&mut self, regularization: Option<Regularization>) {
+        self.regularization = regularization;
+    }
+
+    // Keeps ancestry over the last `depth` resamples, or turns it off with None
+    pub fn set_genealogy(&mut self, depth: Option<usize>) {
+        self.genealogy = depth.map(Genealogy::new);
+    }
+
+    pub fn set_rng(&mut self, rng: SimRng) {
+        self.rng = rng;
+    }
+
+    // Expects normalised weights
+    pub fn effective_sample_size(&self) -> f32 {
+        if self.weights.is_empty() {
+            return self.particles.len() as f32;
+        }
+
+        let sum_sq: f32 = self.weights.iter().map(|x| x * x).sum();
+        1.0 / sum_sq
+    }
+
+    pub fn needs_resample(&self) -> bool {
+        match self.policy {
+            ResamplePolicy::Always => true,
+            ResamplePolicy::Never => false,
+            ResamplePolicy::EssBelow(fraction) => {
+                self.effective_sample_size() < fraction * self.particles.len() as f32
+            }
+        }
+    }
+
+    pub fn resample_with<R: Resampler>(&mut self, resampler: &R) {
+        assert!(self.weights.len() == self.particles.len());
+        swap(&mut self.particles, &mut self.old_particles);
+        self.particles.clear();
+        self.indices.clear();
+
+        let len = self.old_particles.len();
+        resampler.resample(&self.weights, len, &mut self.rng, &mut self.indices);
+
+        self.apply_indices();
+    }
+
+    // Copies the resampled particles out of `old_particles` and records where they
+    // came from
+    fn apply_indices(&mut self) {
+        for &index in self.indices.iter() {
+            self.particles.push(self.old_particles[index]);
+        }
+        if let Some(genealogy) = self.genealogy.as_mut() {
+            genealogy.record(&self.old_particles, &self.indices);
+        }
+        self.reset_weights();
+    }
+
+    // Index of the particle each current particle was copied from at the last resample
+    pub fn ancestors(&self) -> &[usize] {
+        &self.indices
+    }
+
+    pub fn reset<F>(&mut self, mut resampler: F)
+    where
+        F: FnMut() -> T,
+    {
+        let len = self.particles.len();
+        self.particles.clear();
+        for _ in 0..len {
+            self.particles.push(resampler());
+        }
+        self.reset_weights();
+        self.previous.clear();
+        if let Some(genealogy) = self.genealogy.as_mut() {
+            genealogy.clear();
+        }
+    }
+
+    pub fn reset_weights(&mut self) {
+        let len = self.particles.len();
+        let uniform = 1.0 / len as f32;
+        self.weights.clear();
+        self.weights.resize(len, uniform);
+        self.log_weights.clear();
+        self.log_weights.resize(len, uniform.ln());
+    }
+
+    // Adds the log likelihood returned by `func` to each particle's log weight, so
+    // weights carry over between steps that don't resample
+    pub fn reweight<F>(&mut self, mut func: F)
+    where
+        F: FnMut(&T) -> f32,
+    {
+        if self.log_weights.len() != self.particles.len() {
+            self.reset_weights();
+        }
+        for (particle, weight) in self.particles.iter().zip(self.log_weights.iter_mut()) {
+            let log_likelihood = func(particle);
+            *weight = if log_likelihood.is_nan() {
+                f32::NEG_INFINITY
+            } else {
+                *weight + log_likelihood
+            };
+        }
+    }
+
+    // Normalises the log weights with log-sum-exp and refreshes the linear weights.
+    // Returns the log of the sum of the weights before normalisation.
+    pub fn normalize_weights(&mut self) -> Result<f32, DegenerateWeights> {
+        let max = self
+            .log_weights
+            .iter()
+            .fold(f32::NEG_INFINITY, |max, &x| max.max(x));
+        if !max.is_finite() {
+            return Err(DegenerateWeights);
+        }
+
+        let sum: f32 = self.log_weights.iter().map(|x| (x - max).exp()).sum();
+        let log_sum = max + sum.ln();
+
+        self.weights.clear();
+        for weight in self.log_weights.iter_mut() {
+            *weight -= log_sum;
+            self.weights.push(weight.exp());
+        }
+
+        Ok(log_sum)
+    }
+
+    pub fn sum_weights(&self) -> f32 {
+        self.weights.iter().sum()
+    }
+
+    pub fn transition<F>(&mut self, mut func: F)
+    where
+        F: FnMut(&mut T),
+    {
+        self.store_previous();
+        for particle in self.particles.iter_mut() {
+            func(particle);
+        }
+    }
+
+    // Like `transition`, but hands `func` the filter's random number generator
+    pub fn propagate<F>(&mut self, mut func: F)
+    where
+        F: FnMut(&mut T, &mut SimRng),
+    {
+        self.store_previous();
+        for particle in self.particles.iter_mut() {
+            func(particle, &mut self.rng);
+        }
+    }
+
+    fn store_previous(&mut self) {
+        self.previous.clear();
+        self.previous
+            .extend(self.particles.iter().cloned().map(Some));
+    }
+}
+
+impl<T: ParticleState> Filter<T> {
+    // Expects normalised weights
+    pub fn resample(&mut self) {
+        // The kernel is shaped by the weighted posterior, before resampling collapses it
+        let covariance = self.regularization.map(|_| self.covariance());
+
+        match self.kld {
+            Some(kld) => self.kld_resample(&kld),
+            None => {
+                let resampler = self.resampler;
+                self.resample_with(&resampler);
+            }
+        }
+
+        if let (Some(regularization), Some(covariance)) = (self.regularization, covariance) {
+            self.regularize(&regularization, &covariance);
+        }
+    }
+
+    // Replaces `fraction` of the particles, picked at random, with draws from
+    // `proposal`, which returns a particle and the log density it was drawn with.
+    // Weights then target a prior mixing the current particles with `fraction` of
+    // a prior whose log density is given by `log_prior`, so a lost tracker can pick
+    // up particles where the measurement says the animal is. `fraction` is clamped to
+    // [0, 1].
+    pub fn inject<P, F>(&mut self, fraction: f32, mut proposal: P, mut log_prior: F)
+    where
+        P: FnMut(&mut SimRng) -> (T, f32),
+        F: FnMut(&T) -> f32,
+    {
+        let fraction = fraction.clamp(0.0, 1.0);
+        let len = self.particles.len();
+        let count = ((fraction * len as f32).round() as usize).min(len);
+        if count == 0 {
+            return;
+        }
+        if self.log_weights.len() != len {
+            self.reset_weights();
+        }
+
+        let replaced = seq::sample_indices(&mut self.rng, len, count);
+        for &index in replaced.iter() {
+            self.log_weights[index] = f32::NEG_INFINITY;
+        }
+
+        // Kept particles share the remaining prior mass
+        let kept_max = self
+            .log_weights
+            .iter()
+            .fold(f32::NEG_INFINITY, |max, &x| max.max(x));
+        if kept_max.is_finite() {
+            let kept_sum: f32 = self.log_weights.iter().map(|x| (x - kept_max).exp()).sum();
+            let shift = (1.0 - fraction).ln() - (kept_max + kept_sum.ln());
+            self.log_weights.iter_mut().for_each(|x| *x += shift);
+        }
+
+        let share = (fraction / count as f32).ln();
+        for &index in replaced.iter() {
+            let (particle, log_proposal) = proposal(&mut self.rng);
+            self.particles[index] = particle;
+            self.log_weights[index] = share + log_prior(&particle) - log_proposal;
+            if let Some(previous) = self.previous.get_mut(index) {
+                *previous = None;
+            }
+        }
+        if let Some(genealogy) = self.genealogy.as_mut() {
+            genealogy.mark_roots(&replaced);
+        }
+    }
+
+    // First stage of the auxiliary particle filter. `func` gives the log likelihood of
+    // the measurement at a point prediction of each particle, and particles are
+    // resampled by it before being propagated.
+    pub fn auxiliary_preweight<F>(&mut self, mut func: F) -> Result<f32, DegenerateWeights>
+    where
+        F: FnMut(&T) -> f32,
+    {
+        let mut first_stage = Vec::with_capacity(self.particles.len());
+        self.reweight(|x| {
+            let stage = func(x);
+            first_stage.push(stage);
+            stage
+        });
+        let log_sum = self.normalize_weights()?;
+        self.resample();
+
+        self.first_stage.clear();
+        for &index in self.indices.iter() {
+            self.first_stage.push(first_stage[index]);
+        }
+
+        Ok(log_sum)
+    }
+
+    // Second stage of the auxiliary particle filter, called once the resampled
+    // particles are propagated. Weights each by its likelihood over the first stage
+    // likelihood of its ancestor.
+    pub fn auxiliary_correct<F>(&mut self, mut func: F) -> Result<f32, DegenerateWeights>
+    where
+        F: FnMut(&T) -> f32,
+    {
+        assert!(self.first_stage.len() == self.particles.len());
+        let first_stage = mem::take(&mut self.first_stage);
+        let mut stages = first_stage.iter();
+        self.reweight(|x| func(x) - stages.next().unwrap());
+        self.first_stage = first_stage;
+
+        self.normalize_weights()
+    }
+
+    // Resample-move step, call straight after resampling. Runs Metropolis-Hastings on
+    // each particle against `log_target(particle, previous)`, where `previous` is the
+    // state its ancestor had before the last transition. Returns the acceptance rate.
+    pub fn move_step<F>(&mut self, config: &McmcConfig, mut log_target: F) -> f32
+    where
+        F: FnMut(&T, Option<&T>) -> f32,
+    {
+        let indices = &self.indices;
+        let previous_states = &self.previous;
+        let rng = &mut self.rng;
+        let mut accepted = 0;
+        let mut proposals = 0;
+
+        for (i, particle) in self.particles.iter_mut().enumerate() {
+            let previous = indices
+                .get(i)
+                .and_then(|&index| previous_states.get(index))
+                .and_then(|x| x.as_ref());
+            let mut current = log_target(particle, previous);
+
+            for _ in 0..config.iterations {
+                let mut proposed = *particle;
+                proposed.perturb(config.stddev, rng);
+                let target = log_target(&proposed, previous);
+
+                proposals += 1;
+                if rng.gen::<f32>().ln() < target - current {
+                    *particle = proposed;
+                    current = target;
+                    accepted += 1;
+                }
+            }
+        }
+
+        if proposals == 0 {
+            0.0
+        } else {
+            accepted as f32 / proposals as f32
+        }
+    }
+
+    // Jitters every particle with a draw from the regularisation kernel
+    pub fn regularize(&mut self, regularization: &Regularization, covariance: &Mat2) {
+        let kernel = regularization.kernel;
+        let bandwidth = regularization.scale * kernel.optimal_bandwidth(self.particles.len());
+        let shape = regularize::cholesky(covariance) * bandwidth;
+
+        for particle in self.particles.iter_mut() {
+            let offset = shape * kernel.sample(&mut self.rng);
+            let position = particle.position() + offset;
+            particle.set_position(position);
+        }
+    }
+
+    // Resamples according to the filter's policy and returns whether it did
+    pub fn resample_if_needed(&mut self) -> bool {
+        if self.needs_resample() {
+            self.resample();
+            true
+        } else {
+            false
+        }
+    }
+
+    // Draws particles one at a time until enough bins are occupied to satisfy the
+    // KLD bound, so the particle count follows how spread out the posterior is
+    pub fn kld_resample(&mut self, config: &KldConfig) {
+        assert!(self.weights.len() == self.particles.len());
+        if self.particles.is_empty() {
+            return;
+        }
+        swap(&mut self.particles, &mut self.old_particles);
+        self.particles.clear();
+        self.indices.clear();
+
+        let mut cumulative = Vec::with_capacity(self.weights.len());
+        let mut sum = 0.0;
+        for weight in self.weights.iter() {
+            sum += weight;
+            cumulative.push(sum);
+        }
+
+        let mut bins = KldBins::new(config.bin_size);
+        let last = cumulative.len() - 1;
+
+        loop {
+            let position = self.rng.gen::<f32>() * sum;
+            let index = match cumulative.binary_search_by(|x| x.partial_cmp(&position).unwrap()) {
+                Ok(index) | Err(index) => index.min(last),
+            };
+
+            self.indices.push(index);
+            bins.insert(self.old_particles[index].position());
+
+            if self.indices.len() >= config.required_particles(bins.len()) {
+                break;
+            }
+        }
+
+        self.apply_indices();
+    }
+
+    pub fn new_start_box(
+        particles: usize,
+        h_range: Range<f32>,
+        v_range: Range<f32>,
+        rng: SimRng,
+    ) -> Filter<T> {
+        let mut filter = Filter::with_capacity(particles);
+        filter.set_rng(rng);
+
+        for _ in 0..particles {
+            let particle = T::sample_uniform(&h_range, &v_range, &mut filter.rng);
+            filter.particles.push(particle);
+        }
+        filter.reset_weights();
+
+        filter
+    }
+
+    pub fn reset_uniformly(&mut self, h_range: Range<f32>, v_range: Range<f32>) {
+        let len = self.particles.len();
+        self.particles.clear();
+        for _ in 0..len {
+            let particle = T::sample_uniform(&h_range, &v_range, &mut self.rng);
+            self.particles.push(particle);
+        }
+        self.reset_weights();
+        self.previous.clear();
+        if let Some(genealogy) = self.genealogy.as_mut() {
+            genealogy.clear();
+        }
+    }
+
+    // Normalised weights, or uniform ones if the filter hasn't been weighted yet
+    fn current_weights(&self) -> Cow<'_, [f32]> {
+        if self.weights.len() == self.particles.len() {
+            Cow::Borrowed(&self.weights)
+        } else {
+            let len = self.particles.len();
+            Cow::Owned(vec![1.0 / len as f32; len])
+        }
+    }
+
+    fn positions(&self) -> Vec<Vec2> {
+        self.particles.iter().map(|x| x.position()).collect()
+    }
+
+    pub fn guess_state(&self) -> T {
+        T::weighted_mean(&self.particles, &self.current_weights())
+    }
+
+    pub fn guess_position(&self) -> Vec2 {
+        self.guess_state().position()
+    }
+
+    pub fn covariance(&self) -> Mat2 {
+        let mean = self.guess_position();
+        estimate::covariance(&self.positions(), &self.current_weights(), mean)
+    }
+
+    pub fn max_weight_index(&self) -> usize {
+        self.current_weights()
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, w)| OrderedFloat(**w))
+            .map(|(i, _)| i)
+            .unwrap_or(0)
+    }
+
+    pub fn max_weight_particle(&self) -> T {
+        self.particles[self.max_weight_index()]
+    }
+
+    pub fn cluster_mode(&self, cell_size: f32) -> Vec2 {
+        estimate::cluster_mode(&self.positions(), &self.current_weights(), cell_size)
+    }
+
+    pub fn credible_ellipse(&self, probability: f32) -> Ellipse {
+        Ellipse::from_covariance(self.guess_position(), &self.covariance(), probability)
+    }
+
+    // Expects normalised weights from before resampling, which makes them uniform
+    pub fn estimate(&self, cell_size: f32) -> Estimate<T> {
+        let weights = self.current_weights();
+        let positions = self.positions();
+        let mean = self.guess_state();
+        let covariance = estimate::covariance(&positions, &weights, mean.position());
+
+        Estimate {
+            mean,
+            covariance,
+            map: self.max_weight_particle(),
+            mode: estimate::cluster_mode(&positions, &weights, cell_size),
+            credible: Ellipse::from_covariance(mean.position(), &covariance, 0.95),
+            effective_sample_size: self.effective_sample_size(),
+        }
+    }
+
+    pub fn random_movement(&mut self, stddev: f32) {
+        self.store_previous();
+        for particle in self.particles.iter_mut() {
+            particle.perturb(stddev, &mut self.rng);
+        }
+    }
+
+    pub fn write_particle_pos(&self, buffer: &mut [[f32; 2]]) {
+        assert!(buffer.len() >= self.particles.len());
+
+        for (i, particle) in self.particles.iter().enumerate() {
+            buffer[i] = *particle.position().as_ref();
+        }
+    }
+}
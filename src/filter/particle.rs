@@ -0,0 +1,115 @@
+use glm::*;
+use rand::distributions::{Distribution, Normal, Uniform};
+use rand::Rng;
+use std::ops::Range;
+
+// State carried by each particle. Lets `Filter` estimate, diffuse and draw
+// particles without knowing what they contain.
+pub trait ParticleState: Copy {
+    // `weights` are normalised and line up with `particles`
+    fn weighted_mean(particles: &[Self], weights: &[f32]) -> Self;
+    // Adds zero mean gaussian noise with the given stddev
+    fn perturb<R: Rng + ?Sized>(&mut self, stddev: f32, rng: &mut R);
+    fn sample_uniform<R: Rng + ?Sized>(
+        h_range: &Range<f32>,
+        v_range: &Range<f32>,
+        rng: &mut R,
+    ) -> Self;
+    // Position used for rendering and distance based measurements
+    fn position(&self) -> Vec2;
+    fn set_position(&mut self, position: Vec2);
+}
+
+impl ParticleState for Vec2 {
+    fn weighted_mean(particles: &[Vec2], weights: &[f32]) -> Vec2 {
+        particles
+            .iter()
+            .zip(weights.iter())
+            .fold(vec2(0.0, 0.0), |avg, (x, w)| avg + *x * *w)
+    }
+
+    fn perturb<R: Rng + ?Sized>(&mut self, stddev: f32, rng: &mut R) {
+        let normal = Normal::new(0.0, stddev.into());
+        let x = normal.sample(rng) as f32;
+        let y = normal.sample(rng) as f32;
+        *self += vec2(x, y);
+    }
+
+    fn sample_uniform<R: Rng + ?Sized>(
+        h_range: &Range<f32>,
+        v_range: &Range<f32>,
+        rng: &mut R,
+    ) -> Vec2 {
+        let x = Uniform::new(h_range.start, h_range.end).sample(rng);
+        let y = Uniform::new(v_range.start, v_range.end).sample(rng);
+        vec2(x, y)
+    }
+
+    fn position(&self) -> Vec2 {
+        *self
+    }
+
+    fn set_position(&mut self, position: Vec2) {
+        *self = position;
+    }
+}
+
+// Position plus the velocity and turn rate used by the kinematic motion models.
+// Heading is the direction of the velocity.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct KinematicState {
+    pub position: Vec2,
+    pub velocity: Vec2,
+    pub turn_rate: f32,
+}
+
+impl KinematicState {
+    pub fn at(position: Vec2) -> KinematicState {
+        KinematicState {
+            position,
+            velocity: vec2(0.0, 0.0),
+            turn_rate: 0.0,
+        }
+    }
+
+    pub fn heading(&self) -> f32 {
+        self.velocity.y.atan2(self.velocity.x)
+    }
+}
+
+impl ParticleState for KinematicState {
+    fn weighted_mean(particles: &[KinematicState], weights: &[f32]) -> KinematicState {
+        particles.iter().zip(weights.iter()).fold(
+            KinematicState::at(vec2(0.0, 0.0)),
+            |mut avg, (x, w)| {
+                avg.position += x.position * *w;
+                avg.velocity += x.velocity * *w;
+                avg.turn_rate += x.turn_rate * w;
+                avg
+            },
+        )
+    }
+
+    // Perturbs position and velocity, turn rate is left to the motion model
+    fn perturb<R: Rng + ?Sized>(&mut self, stddev: f32, rng: &mut R) {
+        self.position.perturb(stddev, rng);
+        self.velocity.perturb(stddev, rng);
+    }
+
+    // Uniform over the position region, starting at rest
+    fn sample_uniform<R: Rng + ?Sized>(
+        h_range: &Range<f32>,
+        v_range: &Range<f32>,
+        rng: &mut R,
+    ) -> KinematicState {
+        KinematicState::at(Vec2::sample_uniform(h_range, v_range, rng))
+    }
+
+    fn position(&self) -> Vec2 {
+        self.position
+    }
+
+    fn set_position(&mut self, position: Vec2) {
+        self.position = position;
+    }
+}
@@ -0,0 +1,79 @@
+use glm::*;
+use rand::distributions::{Distribution, Normal};
+use rand::Rng;
+use std::f32::consts::PI;
+
+// Position dimensions the kernel is applied over
+const DIMENSIONS: f32 = 2.0;
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub enum Kernel {
+    Gaussian,
+    Epanechnikov,
+}
+
+// Regularised resampling: after resampling, each particle is jittered by a draw from
+// `kernel`, shaped by the particle covariance and scaled by the optimal bandwidth
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Regularization {
+    pub kernel: Kernel,
+    // Multiplies the optimal bandwidth, values below 1 shrink the jitter
+    pub scale: f32,
+}
+
+impl Default for Regularization {
+    fn default() -> Regularization {
+        Regularization {
+            kernel: Kernel::Epanechnikov,
+            scale: 1.0,
+        }
+    }
+}
+
+impl Kernel {
+    // Bandwidth minimising the mean integrated squared error for a gaussian
+    // posterior, h = A * N^(-1 / (d + 4))
+    pub fn optimal_bandwidth(&self, particles: usize) -> f32 {
+        let d = DIMENSIONS;
+        let a = match self {
+            Kernel::Gaussian => (4.0 / (d + 2.0)).powf(1.0 / (d + 4.0)),
+            Kernel::Epanechnikov => {
+                // Volume of the unit circle
+                let c = PI;
+                (8.0 / c * (d + 4.0) * (2.0 * PI.sqrt()).powf(d)).powf(1.0 / (d + 4.0))
+            }
+        };
+
+        a * (particles as f32).powf(-1.0 / (d + 4.0))
+    }
+
+    pub fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Vec2 {
+        match self {
+            Kernel::Gaussian => {
+                let normal = Normal::new(0.0, 1.0);
+                vec2(normal.sample(rng) as f32, normal.sample(rng) as f32)
+            }
+            Kernel::Epanechnikov => {
+                // The squared radius has density 2(1 - u) on the unit disc
+                let u = 1.0 - (1.0 - rng.gen::<f32>()).sqrt();
+                let angle = rng.gen::<f32>() * 2.0 * PI;
+                let (sin, cos) = angle.sin_cos();
+                vec2(cos, sin) * u.sqrt()
+            }
+        }
+    }
+}
+
+// Lower triangular factor of a 2x2 covariance, clamped so flat posteriors don't
+// produce NaNs
+pub fn cholesky(covariance: &Mat2) -> Mat2 {
+    let l11 = covariance[(0, 0)].max(0.0).sqrt();
+    let l21 = if l11 > 0.0 {
+        covariance[(1, 0)] / l11
+    } else {
+        0.0
+    };
+    let l22 = (covariance[(1, 1)] - l21 * l21).max(0.0).sqrt();
+
+    mat2(l11, 0.0, l21, l22)
+}
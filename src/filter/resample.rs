@@ -0,0 +1,231 @@
+use rand::distributions::{Distribution, Uniform};
+use rand::Rng;
+
+// Chooses which particles survive a resampling step. `weights` must be normalised,
+// and `indices` is filled with `count` indices into `weights`.
+pub trait Resampler {
+    fn resample<R: Rng + ?Sized>(
+        &self,
+        weights: &[f32],
+        count: usize,
+        rng: &mut R,
+        indices: &mut Vec<usize>,
+    );
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Multinomial;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Systematic;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Stratified;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Residual;
+
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub enum ResamplingScheme {
+    Multinomial,
+    #[default]
+    Systematic,
+    Stratified,
+    Residual,
+}
+
+// When a filter should resample after reweighting. `EssBelow` resamples once the
+// effective sample size drops below the given fraction of the particle count.
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub enum ResamplePolicy {
+    #[default]
+    Always,
+    Never,
+    EssBelow(f32),
+}
+
+// Walks the cumulative weights once, pushing the index that each of the sorted
+// `positions` falls into.
+fn select_sorted<I>(weights: &[f32], positions: I, indices: &mut Vec<usize>)
+where
+    I: Iterator<Item = f32>,
+{
+    if weights.is_empty() {
+        return;
+    }
+
+    let last = weights.len() - 1;
+    let mut index = 0;
+    let mut cumulative = weights[0];
+
+    for position in positions {
+        while position >= cumulative && index < last {
+            index += 1;
+            cumulative += weights[index];
+        }
+        indices.push(index);
+    }
+}
+
+impl Resampler for Multinomial {
+    fn resample<R: Rng + ?Sized>(
+        &self,
+        weights: &[f32],
+        count: usize,
+        rng: &mut R,
+        indices: &mut Vec<usize>,
+    ) {
+        let uniform = Uniform::new(0.0, 1.0);
+        let mut positions: Vec<f32> = (0..count).map(|_| uniform.sample(rng)).collect();
+        positions.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        select_sorted(weights, positions.into_iter(), indices);
+    }
+}
+
+impl Resampler for Systematic {
+    fn resample<R: Rng + ?Sized>(
+        &self,
+        weights: &[f32],
+        count: usize,
+        rng: &mut R,
+        indices: &mut Vec<usize>,
+    ) {
+        let step = 1.0 / count as f32;
+        let start = rng.gen::<f32>() * step;
+
+        select_sorted(
+            weights,
+            (0..count).map(|i| start + i as f32 * step),
+            indices,
+        );
+    }
+}
+
+impl Resampler for Stratified {
+    fn resample<R: Rng + ?Sized>(
+        &self,
+        weights: &[f32],
+        count: usize,
+        rng: &mut R,
+        indices: &mut Vec<usize>,
+    ) {
+        let step = 1.0 / count as f32;
+        let positions: Vec<f32> = (0..count)
+            .map(|i| (i as f32 + rng.gen::<f32>()) * step)
+            .collect();
+
+        select_sorted(weights, positions.into_iter(), indices);
+    }
+}
+
+impl Resampler for Residual {
+    fn resample<R: Rng + ?Sized>(
+        &self,
+        weights: &[f32],
+        count: usize,
+        rng: &mut R,
+        indices: &mut Vec<usize>,
+    ) {
+        let start = indices.len();
+        let scale = count as f32;
+        let mut residuals = Vec::with_capacity(weights.len());
+        let mut residual_sum = 0.0;
+
+        for (i, weight) in weights.iter().enumerate() {
+            let expected = weight * scale;
+            let copies = expected.floor();
+            for _ in 0..copies as usize {
+                indices.push(i);
+            }
+            residuals.push(expected - copies);
+            residual_sum += expected - copies;
+        }
+
+        indices.truncate(start + count);
+        let remaining = count - (indices.len() - start);
+        if remaining == 0 {
+            return;
+        }
+
+        if residual_sum > 0.0 {
+            residuals.iter_mut().for_each(|x| *x /= residual_sum);
+            Multinomial.resample(&residuals, remaining, rng, indices);
+        } else {
+            Multinomial.resample(weights, remaining, rng, indices);
+        }
+    }
+}
+
+impl Resampler for ResamplingScheme {
+    fn resample<R: Rng + ?Sized>(
+        &self,
+        weights: &[f32],
+        count: usize,
+        rng: &mut R,
+        indices: &mut Vec<usize>,
+    ) {
+        match self {
+            ResamplingScheme::Multinomial => Multinomial.resample(weights, count, rng, indices),
+            ResamplingScheme::Systematic => Systematic.resample(weights, count, rng, indices),
+            ResamplingScheme::Stratified => Stratified.resample(weights, count, rng, indices),
+            ResamplingScheme::Residual => Residual.resample(weights, count, rng, indices),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use random;
+
+    const SCHEMES: [ResamplingScheme; 4] = [
+        ResamplingScheme::Multinomial,
+        ResamplingScheme::Systematic,
+        ResamplingScheme::Stratified,
+        ResamplingScheme::Residual,
+    ];
+
+    fn copies(scheme: ResamplingScheme, weights: &[f32], count: usize) -> Vec<usize> {
+        let mut rng = random::stream(7, 0);
+        let mut indices = vec![];
+        scheme.resample(weights, count, &mut rng, &mut indices);
+
+        assert_eq!(indices.len(), count);
+        let mut copies = vec![0; weights.len()];
+        for &index in indices.iter() {
+            assert!(index < weights.len());
+            copies[index] += 1;
+        }
+        copies
+    }
+
+    #[test]
+    fn every_scheme_returns_count_indices_in_range() {
+        let weights = [0.1, 0.4, 0.0, 0.3, 0.2];
+        for &scheme in SCHEMES.iter() {
+            for &count in [1, 5, 13, 100].iter() {
+                copies(scheme, &weights, count);
+            }
+        }
+    }
+
+    #[test]
+    fn residual_and_systematic_copy_close_to_expected() {
+        let weights = [0.05, 0.45, 0.0, 0.3, 0.2];
+        let count = 100;
+        for &scheme in [ResamplingScheme::Systematic, ResamplingScheme::Residual].iter() {
+            let copies = copies(scheme, &weights, count);
+            for (&weight, &copies) in weights.iter().zip(copies.iter()) {
+                let expected = weight * count as f32;
+                assert!(
+                    (copies as f32 - expected).abs() <= 1.0,
+                    "{:?} made {} copies, expected {}",
+                    scheme,
+                    copies,
+                    expected
+                );
+            }
+        }
+    }
+}
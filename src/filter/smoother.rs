@@ -0,0 +1,205 @@
+use filter::particle::ParticleState;
+use filter::Filter;
+use glm::*;
+use rand::Rng;
+use std::collections::VecDeque;
+use std::f32;
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct SmootherConfig {
+    // Steps of history kept, older steps are dropped
+    pub history: usize,
+    // Paths drawn by backward simulation and averaged into the smoothed trajectory
+    pub trajectories: usize,
+}
+
+impl Default for SmootherConfig {
+    fn default() -> SmootherConfig {
+        SmootherConfig {
+            history: 200,
+            trajectories: 50,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Step<T> {
+    particles: Vec<T>,
+    log_weights: Vec<f32>,
+    // Time since the previous step
+    dt: f32,
+}
+
+// Forward-filtering backward-smoothing. Keeps the weighted particles of each filter
+// step and draws whole trajectories backwards through them.
+#[derive(Clone, Debug)]
+pub struct Smoother<T> {
+    steps: VecDeque<Step<T>>,
+    pub config: SmootherConfig,
+}
+
+// Draws an index from unnormalised log weights, or None if they are all zero
+fn sample_log<R: Rng + ?Sized>(log_weights: &[f32], rng: &mut R) -> Option<usize> {
+    let max = log_weights
+        .iter()
+        .fold(f32::NEG_INFINITY, |max, &x| max.max(x));
+    if !max.is_finite() {
+        return None;
+    }
+
+    let sum: f32 = log_weights.iter().map(|x| (x - max).exp()).sum();
+    let mut target = rng.gen::<f32>() * sum;
+    for (i, weight) in log_weights.iter().enumerate() {
+        target -= (weight - max).exp();
+        if target <= 0.0 {
+            return Some(i);
+        }
+    }
+
+    Some(log_weights.len() - 1)
+}
+
+impl<T: ParticleState> Smoother<T> {
+    pub fn new(config: SmootherConfig) -> Smoother<T> {
+        Smoother {
+            steps: VecDeque::with_capacity(config.history),
+            config,
+        }
+    }
+
+    // Call after the filter's weights are normalised and before it resamples. `dt` is
+    // the time the particles were propagated by since the last step.
+    pub fn record(&mut self, filter: &Filter<T>, dt: f32) {
+        if self.config.history == 0 {
+            return;
+        }
+        if self.steps.len() == self.config.history {
+            self.steps.pop_front();
+        }
+
+        self.steps.push_back(Step {
+            particles: filter.particles.clone(),
+            log_weights: filter.log_weights.clone(),
+            dt,
+        });
+    }
+
+    // Forgets the newest `steps` steps, for when the filter is rolled back
+    pub fn rewind(&mut self, steps: usize) {
+        let len = self.steps.len();
+        self.steps.truncate(len - steps.min(len));
+    }
+
+    // Backward simulation. `log_transition(from, to, dt)` is the log density of the
+    // motion model moving a particle from one step to the next. Each returned path
+    // holds one state per recorded step, oldest first.
+    pub fn backward_sample<F, R>(
+        &self,
+        trajectories: usize,
+        log_transition: F,
+        rng: &mut R,
+    ) -> Vec<Vec<T>>
+    where
+        F: Fn(&T, &T, f32) -> f32,
+        R: Rng + ?Sized,
+    {
+        let last = match self.steps.back() {
+            Some(last) => last,
+            None => return vec![],
+        };
+
+        let mut paths = Vec::with_capacity(trajectories);
+        let mut backward = vec![];
+
+        for _ in 0..trajectories {
+            let mut path = Vec::with_capacity(self.steps.len());
+            let index = sample_log(&last.log_weights, rng).unwrap_or(0);
+            let mut next = last.particles[index];
+            let mut dt = last.dt;
+            path.push(next);
+
+            for step in self.steps.iter().rev().skip(1) {
+                backward.clear();
+                for (particle, weight) in step.particles.iter().zip(step.log_weights.iter()) {
+                    backward.push(weight + log_transition(particle, &next, dt));
+                }
+
+                // The filter was reset between these steps, so the motion model
+                // can't link them and only the filtering weights are used
+                let index = match sample_log(&backward, rng) {
+                    Some(index) => index,
+                    None => sample_log(&step.log_weights, rng).unwrap_or(0),
+                };
+                next = step.particles[index];
+                dt = step.dt;
+                path.push(next);
+            }
+
+            path.reverse();
+            paths.push(path);
+        }
+
+        paths
+    }
+
+    // Mean position at each recorded step over the backward sampled paths
+    pub fn smoothed_positions<F, R>(&self, log_transition: F, rng: &mut R) -> Vec<Vec2>
+    where
+        F: Fn(&T, &T, f32) -> f32,
+        R: Rng + ?Sized,
+    {
+        let paths = self.backward_sample(self.config.trajectories, log_transition, rng);
+        if paths.is_empty() {
+            return vec![];
+        }
+
+        let one_on_len = 1.0 / paths.len() as f32;
+        let mut positions = vec![vec2(0.0, 0.0); self.steps.len()];
+        for path in paths.iter() {
+            for (position, state) in positions.iter_mut().zip(path.iter()) {
+                *position += state.position() * one_on_len;
+            }
+        }
+
+        positions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use filter::particle::KinematicState;
+    use motion::{MotionModel, RandomWalk};
+    use random;
+
+    #[test]
+    fn backward_sampling_gives_a_finite_state_per_recorded_step() {
+        let motion = RandomWalk { stddev: 0.5 };
+        let mut filter: Filter<KinematicState> =
+            Filter::new_start_box(50, -5.0..5.0, -5.0..5.0, random::stream(9, 0));
+        let mut smoother = Smoother::new(SmootherConfig {
+            history: 10,
+            trajectories: 5,
+        });
+
+        for step in 0..4 {
+            let target = vec2(step as f32, 0.0);
+            filter.propagate(|particle, rng| motion.propagate(particle, 1.0, rng));
+            filter.reweight(|particle| -length2(&(particle.position - target)));
+            filter.normalize_weights().unwrap();
+            smoother.record(&filter, 1.0);
+            filter.resample();
+        }
+
+        let log_transition =
+            |from: &KinematicState, to: &KinematicState, dt: f32| motion.log_density(from, to, dt);
+        let mut rng = random::stream(9, 1);
+        let paths = smoother.backward_sample(5, log_transition, &mut rng);
+        assert_eq!(paths.len(), 5);
+        assert!(paths.iter().all(|path| path.len() == 4));
+
+        let positions = smoother.smoothed_positions(log_transition, &mut rng);
+        assert_eq!(positions.len(), 4);
+        assert!(positions.iter().all(|x| x.x.is_finite() && x.y.is_finite()));
+    }
+}
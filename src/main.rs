@@ -12,12 +12,18 @@ extern crate serde_derive;
 extern crate rayon;
 extern crate serde_json;
 
+mod association;
+mod detection;
 mod filter;
 mod input;
 mod measurement;
+mod motion;
+mod random;
 mod renderer;
 mod state;
 mod support;
+mod telemetry;
+mod track;
 mod tracker;
 mod util;
 mod window;
@@ -57,12 +63,14 @@ fn main() {
                     };
                     let x = pos.x / dims.width as f32 * 2.0 - 1.0;
                     let y = -pos.y / dims.height as f32 * 2.0 + 1.0;
-                    state.drone_pos = glm::vec2(x, y) * 10.0;
+                    if let Some(drone) = state.drones.first_mut() {
+                        drone.position = glm::vec2(x, y) * 10.0;
+                    }
                 }
             }
-            if measurement_timer >= 0.25 {
-                measurement_timer -= 0.25;
-                state.update(time);
+            if measurement_timer >= state::MEASUREMENT_PERIOD {
+                measurement_timer -= state::MEASUREMENT_PERIOD;
+                state.update(state::MEASUREMENT_PERIOD);
             }
 
             state.update_renderer(display);
@@ -74,4 +82,6 @@ fn main() {
                 .unwrap();
         },
     );
+
+    state.save_report().expect("Failed to save report");
 }
@@ -1,19 +1,539 @@
 use glm::*;
 use rand::distributions::{Distribution, Normal};
-use statrs::function::erf;
-use std::f32::consts::FRAC_1_SQRT_2;
+use rand::Rng;
+use statrs::function::{erf, gamma};
+use std::f32::consts::PI;
+use std::f64::consts::FRAC_1_SQRT_2;
 
-// Returns z score of the distance between the particle and the drone
+// A single reading from the drone's receiver. Angles are in radians from the x axis,
+// measured at the drone.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Measurement {
+    Range(f32),
+    Bearing(f32),
+    RangeBearing { range: f32, bearing: f32 },
+    // Received signal strength in dBm
+    Rssi(f32),
+    // Time difference of arrival at each ground receiver relative to the drone, as a
+    // distance
+    Tdoa(Vec<f32>),
+}
+
+// What the drone can observe of an animal
+pub trait MeasurementModel {
+    fn generate<R: Rng + ?Sized>(&self, drone: Vec2, animal: Vec2, rng: &mut R) -> Measurement;
+    // Log likelihood, up to a constant, of `measurement` if the animal is at `particle`
+    fn log_likelihood(&self, drone: Vec2, particle: Vec2, measurement: &Measurement) -> f32;
+    // Draws a position the measurement points at along with the log density of
+    // drawing it, if the model can place the animal from a single measurement
+    fn sample_position<R: Rng + ?Sized>(
+        &self,
+        drone: Vec2,
+        measurement: &Measurement,
+        rng: &mut R,
+    ) -> Option<(Vec2, f32)>;
+}
+
+// Density of the measurement error, each scaled to have standard deviation `stddev`
+// where it has one
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub enum Likelihood {
+    // Two sided p-value of the error's z score. Not a density, kept so configs written
+    // before the other likelihoods existed still reproduce.
+    LegacyPValue,
+    #[default]
+    Gaussian,
+    // Heavy tailed, `stddev` is the scale. Approaches a gaussian as `dof` grows.
+    StudentT {
+        dof: f32,
+    },
+    Laplace,
+}
+
+impl Likelihood {
+    // Log density of `error`, normalised so it can be mixed with a clutter density
+    pub fn log_density(&self, error: f32, stddev: f32) -> f32 {
+        let z = error / stddev;
+        match *self {
+            Likelihood::LegacyPValue => log_p_value(z),
+            Likelihood::Gaussian => -0.5 * z * z - (stddev * (2.0 * PI).sqrt()).ln(),
+            Likelihood::StudentT { dof } => {
+                let nu = dof as f64;
+                let norm = gamma::ln_gamma(0.5 * (nu + 1.0))
+                    - gamma::ln_gamma(0.5 * nu)
+                    - 0.5 * (nu * ::std::f64::consts::PI).ln();
+                norm as f32 - 0.5 * (dof + 1.0) * (z * z / dof).ln_1p() - stddev.ln()
+            }
+            Likelihood::Laplace => {
+                let scale = stddev * FRAC_1_SQRT_2 as f32;
+                -error.abs() / scale - (2.0 * scale).ln()
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct RangeModel {
+    pub stddev: f32,
+    #[serde(default)]
+    pub likelihood: Likelihood,
+}
+
+// Angle of arrival from a directional antenna
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct BearingModel {
+    pub stddev: f32,
+    #[serde(default)]
+    pub likelihood: Likelihood,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct RangeBearingModel {
+    pub range_stddev: f32,
+    pub bearing_stddev: f32,
+    #[serde(default)]
+    pub likelihood: Likelihood,
+}
+
+// Log-distance path loss. The collar's signal is received at `reference_power` dBm
+// from `reference_distance` away and falls off by 10 * `exponent` dB per decade of
+// distance, with gaussian shadowing in dB.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct RssiModel {
+    pub reference_power: f32,
+    pub reference_distance: f32,
+    pub exponent: f32,
+    pub shadowing_stddev: f32,
+    #[serde(default)]
+    pub likelihood: Likelihood,
+}
+
+// Time difference of arrival between the drone and ground receivers. Each difference
+// constrains the animal to a hyperbola with the two receivers as foci. `stddev` is
+// the noise of each difference, converted to a distance. The drone's arrival time is
+// in every difference, so its noise is shared and the differences are correlated.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TdoaModel {
+    pub stddev: f32,
+    #[serde(default)]
+    pub likelihood: Likelihood,
+    // Filled in from `Config::receivers`
+    #[serde(skip)]
+    pub receivers: Vec<Vec2>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum MeasurementKind {
+    Range(RangeModel),
+    Bearing(BearingModel),
+    RangeBearing(RangeBearingModel),
+    Rssi(RssiModel),
+    Tdoa(TdoaModel),
+}
+
+// Wraps an angle into [-pi, pi)
+pub fn wrap_angle(angle: f32) -> f32 {
+    let two_pi = 2.0 * PI;
+    angle - two_pi * ((angle + PI) / two_pi).floor()
+}
+
+pub fn bearing(drone: Vec2, animal: Vec2) -> f32 {
+    let offset = animal - drone;
+    offset.y.atan2(offset.x)
+}
+
+// Log of the two sided p-value of a z score
+fn log_p_value(score: f32) -> f32 {
+    erf::erfc((score as f64).abs() * FRAC_1_SQRT_2).ln() as f32
+}
+
+fn gaussian<R: Rng + ?Sized>(stddev: f32, rng: &mut R) -> f32 {
+    Normal::new(0.0, stddev as f64).sample(rng) as f32
+}
+
+fn log_range_likelihood(
+    drone: Vec2,
+    particle: Vec2,
+    measured: f32,
+    stddev: f32,
+    likelihood: &Likelihood,
+) -> f32 {
+    likelihood.log_density(distance(&drone, &particle) - measured, stddev)
+}
+
+fn log_bearing_likelihood(
+    drone: Vec2,
+    particle: Vec2,
+    measured: f32,
+    stddev: f32,
+    likelihood: &Likelihood,
+) -> f32 {
+    likelihood.log_density(wrap_angle(bearing(drone, particle) - measured), stddev)
+}
+
+// Two sided p-value of the range error, the legacy likelihood
 pub fn weighting(drone: Vec2, particle: Vec2, measurement: f32, stddev: f32) -> f32 {
+    log_weighting(drone, particle, measurement, stddev).exp()
+}
+
+// Log of `weighting`, computed in f64 so far away particles don't all round to zero
+pub fn log_weighting(drone: Vec2, particle: Vec2, measurement: f32, stddev: f32) -> f32 {
     let length = distance(&drone, &particle);
-    let score = (length - measurement) / stddev;
-    let value = 1.0 - erf::erf((score.abs() * FRAC_1_SQRT_2) as f64) as f32;
-    value
+    log_p_value((length - measurement) / stddev)
 }
 
-pub fn generate_measurement(drone: Vec2, animal: Vec2, stddev: f32) -> f32 {
+pub fn generate_measurement<R: Rng + ?Sized>(
+    drone: Vec2,
+    animal: Vec2,
+    stddev: f32,
+    rng: &mut R,
+) -> f32 {
     let distance = distance(&drone, &animal);
     let normal = Normal::new(0.0, stddev as f64);
 
-    distance + normal.sample(&mut rand::thread_rng()) as f32
+    distance + normal.sample(rng) as f32
+}
+
+impl RssiModel {
+    // Mean received power at `distance`, closer than a millimetre is treated as a
+    // millimetre
+    pub fn mean_power(&self, distance: f32) -> f32 {
+        let ratio = distance.max(1e-3) / self.reference_distance;
+        self.reference_power - 10.0 * self.exponent * ratio.log10()
+    }
+
+    // Distance the mean received power equals `power` at
+    pub fn distance(&self, power: f32) -> f32 {
+        let decades = (self.reference_power - power) / (10.0 * self.exponent);
+        self.reference_distance * 10f32.powf(decades)
+    }
+}
+
+pub fn generate_rssi<R: Rng + ?Sized>(
+    drone: Vec2,
+    animal: Vec2,
+    model: &RssiModel,
+    rng: &mut R,
+) -> f32 {
+    let power = model.mean_power(distance(&drone, &animal));
+    let normal = Normal::new(0.0, model.shadowing_stddev as f64);
+
+    power + normal.sample(rng) as f32
+}
+
+// Log likelihood of receiving `power` dBm from a collar at `particle`
+pub fn log_rssi_weighting(drone: Vec2, particle: Vec2, power: f32, model: &RssiModel) -> f32 {
+    let expected = model.mean_power(distance(&drone, &particle));
+    model
+        .likelihood
+        .log_density(power - expected, model.shadowing_stddev)
+}
+
+// Draws a position on the ring of radius `measurement` around the drone, with the
+// radius spread by the measurement noise. Returns the position and the log density
+// of drawing it.
+pub fn sample_annulus<R: Rng + ?Sized>(
+    drone: Vec2,
+    measurement: f32,
+    stddev: f32,
+    rng: &mut R,
+) -> (Vec2, f32) {
+    let normal = Normal::new(measurement as f64, stddev as f64);
+    let radius = (normal.sample(rng) as f32).abs().max(1e-6);
+    let angle = rng.gen::<f32>() * 2.0 * PI;
+    let position = drone + vec2(angle.cos(), angle.sin()) * radius;
+
+    // Negative radii are folded back, so both sides of zero contribute
+    let density = |r: f32| {
+        let z = (r - measurement) / stddev;
+        (-0.5 * z * z).exp() / (stddev * (2.0 * PI).sqrt())
+    };
+    let radial = density(radius) + density(-radius);
+
+    (position, radial.ln() - (2.0 * PI * radius).ln())
+}
+
+impl MeasurementModel for RangeModel {
+    fn generate<R: Rng + ?Sized>(&self, drone: Vec2, animal: Vec2, rng: &mut R) -> Measurement {
+        Measurement::Range(generate_measurement(drone, animal, self.stddev, rng))
+    }
+
+    fn log_likelihood(&self, drone: Vec2, particle: Vec2, measurement: &Measurement) -> f32 {
+        match *measurement {
+            Measurement::Range(range) | Measurement::RangeBearing { range, .. } => {
+                log_range_likelihood(drone, particle, range, self.stddev, &self.likelihood)
+            }
+            _ => 0.0,
+        }
+    }
+
+    fn sample_position<R: Rng + ?Sized>(
+        &self,
+        drone: Vec2,
+        measurement: &Measurement,
+        rng: &mut R,
+    ) -> Option<(Vec2, f32)> {
+        match *measurement {
+            Measurement::Range(range) | Measurement::RangeBearing { range, .. } => {
+                Some(sample_annulus(drone, range, self.stddev, rng))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl MeasurementModel for BearingModel {
+    fn generate<R: Rng + ?Sized>(&self, drone: Vec2, animal: Vec2, rng: &mut R) -> Measurement {
+        let angle = bearing(drone, animal) + gaussian(self.stddev, rng);
+        Measurement::Bearing(wrap_angle(angle))
+    }
+
+    fn log_likelihood(&self, drone: Vec2, particle: Vec2, measurement: &Measurement) -> f32 {
+        match *measurement {
+            Measurement::Bearing(bearing) | Measurement::RangeBearing { bearing, .. } => {
+                log_bearing_likelihood(drone, particle, bearing, self.stddev, &self.likelihood)
+            }
+            _ => 0.0,
+        }
+    }
+
+    // A bearing alone doesn't say how far along the ray the animal is
+    fn sample_position<R: Rng + ?Sized>(
+        &self,
+        _drone: Vec2,
+        _measurement: &Measurement,
+        _rng: &mut R,
+    ) -> Option<(Vec2, f32)> {
+        None
+    }
+}
+
+impl RangeBearingModel {
+    fn log_range(&self, drone: Vec2, particle: Vec2, range: f32) -> f32 {
+        log_range_likelihood(drone, particle, range, self.range_stddev, &self.likelihood)
+    }
+
+    fn log_bearing(&self, drone: Vec2, particle: Vec2, bearing: f32) -> f32 {
+        log_bearing_likelihood(
+            drone,
+            particle,
+            bearing,
+            self.bearing_stddev,
+            &self.likelihood,
+        )
+    }
+}
+
+impl MeasurementModel for RangeBearingModel {
+    fn generate<R: Rng + ?Sized>(&self, drone: Vec2, animal: Vec2, rng: &mut R) -> Measurement {
+        let range = generate_measurement(drone, animal, self.range_stddev, rng);
+        let angle = bearing(drone, animal) + gaussian(self.bearing_stddev, rng);
+        Measurement::RangeBearing {
+            range,
+            bearing: wrap_angle(angle),
+        }
+    }
+
+    fn log_likelihood(&self, drone: Vec2, particle: Vec2, measurement: &Measurement) -> f32 {
+        match *measurement {
+            Measurement::RangeBearing { range, bearing } => {
+                self.log_range(drone, particle, range) + self.log_bearing(drone, particle, bearing)
+            }
+            Measurement::Range(range) => self.log_range(drone, particle, range),
+            Measurement::Bearing(bearing) => self.log_bearing(drone, particle, bearing),
+            _ => 0.0,
+        }
+    }
+
+    // Draws the range and bearing independently, which is the measurement density
+    // in polar coordinates divided by the radius
+    fn sample_position<R: Rng + ?Sized>(
+        &self,
+        drone: Vec2,
+        measurement: &Measurement,
+        rng: &mut R,
+    ) -> Option<(Vec2, f32)> {
+        let (range, measured) = match *measurement {
+            Measurement::RangeBearing { range, bearing } => (range, bearing),
+            Measurement::Range(range) => {
+                return Some(sample_annulus(drone, range, self.range_stddev, rng))
+            }
+            _ => return None,
+        };
+
+        let radius = (range + gaussian(self.range_stddev, rng)).abs().max(1e-6);
+        let offset = gaussian(self.bearing_stddev, rng);
+        let angle = measured + offset;
+        let position = drone + vec2(angle.cos(), angle.sin()) * radius;
+
+        let log_normal = |x: f32, stddev: f32| {
+            let z = x / stddev;
+            -0.5 * z * z - (stddev * (2.0 * PI).sqrt()).ln()
+        };
+        // Negative radii are folded back, as in `sample_annulus`
+        let radial = (log_normal(radius - range, self.range_stddev).exp()
+            + log_normal(-radius - range, self.range_stddev).exp())
+        .ln();
+        let log_q = radial + log_normal(offset, self.bearing_stddev) - radius.ln();
+
+        Some((position, log_q))
+    }
+}
+
+impl MeasurementModel for RssiModel {
+    fn generate<R: Rng + ?Sized>(&self, drone: Vec2, animal: Vec2, rng: &mut R) -> Measurement {
+        Measurement::Rssi(generate_rssi(drone, animal, self, rng))
+    }
+
+    fn log_likelihood(&self, drone: Vec2, particle: Vec2, measurement: &Measurement) -> f32 {
+        match *measurement {
+            Measurement::Rssi(power) => log_rssi_weighting(drone, particle, power, self),
+            _ => 0.0,
+        }
+    }
+
+    // Draws a power around the measured one and places it on the ring at the distance
+    // that power implies, so the radius is log-normal
+    fn sample_position<R: Rng + ?Sized>(
+        &self,
+        drone: Vec2,
+        measurement: &Measurement,
+        rng: &mut R,
+    ) -> Option<(Vec2, f32)> {
+        let power = match *measurement {
+            Measurement::Rssi(power) => power,
+            _ => return None,
+        };
+
+        let offset = gaussian(self.shadowing_stddev, rng);
+        let radius = self.distance(power + offset).max(1e-6);
+        let angle = rng.gen::<f32>() * 2.0 * PI;
+        let position = drone + vec2(angle.cos(), angle.sin()) * radius;
+
+        let z = offset / self.shadowing_stddev;
+        let log_power = -0.5 * z * z - (self.shadowing_stddev * (2.0 * PI).sqrt()).ln();
+        // Change of variables from power to radius, |dP/dr| = 10 n / (r ln 10)
+        let log_jacobian = (10.0 * self.exponent / (radius * 10f32.ln())).ln();
+        let log_q = log_power + log_jacobian - (2.0 * PI * radius).ln();
+
+        Some((position, log_q))
+    }
+}
+
+impl TdoaModel {
+    // Noise of each arrival time, so that a difference of two has `stddev`
+    fn arrival_stddev(&self) -> f32 {
+        self.stddev * FRAC_1_SQRT_2 as f32
+    }
+
+    // Extra distance to each receiver over the distance to the drone
+    fn differences<'a>(&'a self, drone: Vec2, animal: Vec2) -> impl Iterator<Item = f32> + 'a {
+        let to_drone = distance(&drone, &animal);
+        self.receivers
+            .iter()
+            .map(move |receiver| distance(receiver, &animal) - to_drone)
+    }
+}
+
+impl MeasurementModel for TdoaModel {
+    fn generate<R: Rng + ?Sized>(&self, drone: Vec2, animal: Vec2, rng: &mut R) -> Measurement {
+        let normal = Normal::new(0.0, self.arrival_stddev() as f64);
+        let at_drone = normal.sample(rng) as f32;
+        let differences = self
+            .differences(drone, animal)
+            .map(|x| x + normal.sample(rng) as f32 - at_drone)
+            .collect();
+
+        Measurement::Tdoa(differences)
+    }
+
+    fn log_likelihood(&self, drone: Vec2, particle: Vec2, measurement: &Measurement) -> f32 {
+        let measured = match measurement {
+            Measurement::Tdoa(measured) => measured,
+            _ => return 0.0,
+        };
+        if measured.len() != self.receivers.len() {
+            return f32::NEG_INFINITY;
+        }
+
+        // The errors have covariance s^2 (I + 11^T) for arrival noise s. They are
+        // whitened by (I - c 11^T) / s, which leaves independent errors with the
+        // arrival noise and a Jacobian of 1 / sqrt(k + 1).
+        let errors: Vec<f32> = self
+            .differences(drone, particle)
+            .zip(measured.iter())
+            .map(|(x, measured)| x - measured)
+            .collect();
+        let k = errors.len() as f32;
+        let c = (1.0 - 1.0 / (k + 1.0).sqrt()) / k;
+        let shared = c * errors.iter().sum::<f32>();
+        let stddev = self.arrival_stddev();
+
+        errors
+            .iter()
+            .map(|error| self.likelihood.log_density(error - shared, stddev))
+            .sum::<f32>()
+            - 0.5 * (k + 1.0).ln()
+    }
+
+    // The intersection of hyperbolas has no simple density to sample from
+    fn sample_position<R: Rng + ?Sized>(
+        &self,
+        _drone: Vec2,
+        _measurement: &Measurement,
+        _rng: &mut R,
+    ) -> Option<(Vec2, f32)> {
+        None
+    }
+}
+
+impl MeasurementKind {
+    pub fn likelihood(&self) -> Likelihood {
+        match self {
+            MeasurementKind::Range(model) => model.likelihood,
+            MeasurementKind::Bearing(model) => model.likelihood,
+            MeasurementKind::RangeBearing(model) => model.likelihood,
+            MeasurementKind::Rssi(model) => model.likelihood,
+            MeasurementKind::Tdoa(model) => model.likelihood,
+        }
+    }
+}
+
+impl MeasurementModel for MeasurementKind {
+    fn generate<R: Rng + ?Sized>(&self, drone: Vec2, animal: Vec2, rng: &mut R) -> Measurement {
+        match self {
+            MeasurementKind::Range(model) => model.generate(drone, animal, rng),
+            MeasurementKind::Bearing(model) => model.generate(drone, animal, rng),
+            MeasurementKind::RangeBearing(model) => model.generate(drone, animal, rng),
+            MeasurementKind::Rssi(model) => model.generate(drone, animal, rng),
+            MeasurementKind::Tdoa(model) => model.generate(drone, animal, rng),
+        }
+    }
+
+    fn log_likelihood(&self, drone: Vec2, particle: Vec2, measurement: &Measurement) -> f32 {
+        match self {
+            MeasurementKind::Range(model) => model.log_likelihood(drone, particle, measurement),
+            MeasurementKind::Bearing(model) => model.log_likelihood(drone, particle, measurement),
+            MeasurementKind::RangeBearing(model) => {
+                model.log_likelihood(drone, particle, measurement)
+            }
+            MeasurementKind::Rssi(model) => model.log_likelihood(drone, particle, measurement),
+            MeasurementKind::Tdoa(model) => model.log_likelihood(drone, particle, measurement),
+        }
+    }
+
+    fn sample_position<R: Rng + ?Sized>(
+        &self,
+        drone: Vec2,
+        measurement: &Measurement,
+        rng: &mut R,
+    ) -> Option<(Vec2, f32)> {
+        match self {
+            MeasurementKind::Range(model) => model.sample_position(drone, measurement, rng),
+            MeasurementKind::Bearing(model) => model.sample_position(drone, measurement, rng),
+            MeasurementKind::RangeBearing(model) => model.sample_position(drone, measurement, rng),
+            MeasurementKind::Rssi(model) => model.sample_position(drone, measurement, rng),
+            MeasurementKind::Tdoa(model) => model.sample_position(drone, measurement, rng),
+        }
+    }
 }
@@ -0,0 +1,231 @@
+use filter::particle::KinematicState;
+use glm::*;
+use rand::distributions::{Distribution, Normal};
+use rand::Rng;
+use std::f32::consts::PI;
+
+// Process model moving a tracker's particles forward in time. Noise is given per
+// square root second, so it scales with the time between measurements.
+pub trait MotionModel {
+    // Moves `state` forward by `dt` seconds, including process noise
+    fn propagate<R: Rng + ?Sized>(&self, state: &mut KinematicState, dt: f32, rng: &mut R);
+    // Noise free prediction of `state` after `dt` seconds
+    fn predict(&self, state: &KinematicState, dt: f32) -> KinematicState;
+    // Log density, up to a constant, of moving from `from` to `to` in `dt` seconds
+    fn log_density(&self, from: &KinematicState, to: &KinematicState, dt: f32) -> f32;
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct RandomWalk {
+    pub stddev: f32,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct ConstantVelocity {
+    pub position_stddev: f32,
+    pub velocity_stddev: f32,
+}
+
+// Constant speed with a slowly varying turn rate
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct CoordinatedTurn {
+    pub position_stddev: f32,
+    pub velocity_stddev: f32,
+    pub turn_rate_stddev: f32,
+}
+
+// Mean reverting walk around a home range centre. `reversion` is the rate the
+// animal is pulled back towards `home`.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct OrnsteinUhlenbeck {
+    pub home: [f32; 2],
+    pub reversion: f32,
+    pub stddev: f32,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub enum MotionKind {
+    RandomWalk(RandomWalk),
+    ConstantVelocity(ConstantVelocity),
+    CoordinatedTurn(CoordinatedTurn),
+    OrnsteinUhlenbeck(OrnsteinUhlenbeck),
+}
+
+fn gaussian<R: Rng + ?Sized>(stddev: f32, rng: &mut R) -> f32 {
+    if stddev <= 0.0 {
+        return 0.0;
+    }
+    Normal::new(0.0, stddev as f64).sample(rng) as f32
+}
+
+fn gaussian_vec<R: Rng + ?Sized>(stddev: f32, rng: &mut R) -> Vec2 {
+    vec2(gaussian(stddev, rng), gaussian(stddev, rng))
+}
+
+// Log of a zero mean isotropic gaussian density over `dimensions` dimensions,
+// including the normalising term so models with different spreads compare
+fn log_gaussian(squared: f32, stddev: f32, dimensions: f32) -> f32 {
+    let variance = (stddev * stddev).max(1e-12);
+    -0.5 * squared / variance - 0.5 * dimensions * (2.0 * PI * variance).ln()
+}
+
+impl MotionModel for RandomWalk {
+    fn propagate<R: Rng + ?Sized>(&self, state: &mut KinematicState, dt: f32, rng: &mut R) {
+        state.position += gaussian_vec(self.stddev * dt.sqrt(), rng);
+        state.velocity = vec2(0.0, 0.0);
+    }
+
+    fn predict(&self, state: &KinematicState, _dt: f32) -> KinematicState {
+        KinematicState::at(state.position)
+    }
+
+    fn log_density(&self, from: &KinematicState, to: &KinematicState, dt: f32) -> f32 {
+        let squared = distance2(&from.position, &to.position);
+        log_gaussian(squared, self.stddev * dt.sqrt(), 2.0)
+    }
+}
+
+impl MotionModel for ConstantVelocity {
+    fn propagate<R: Rng + ?Sized>(&self, state: &mut KinematicState, dt: f32, rng: &mut R) {
+        *state = self.predict(state, dt);
+        state.position += gaussian_vec(self.position_stddev * dt.sqrt(), rng);
+        state.velocity += gaussian_vec(self.velocity_stddev * dt.sqrt(), rng);
+    }
+
+    fn predict(&self, state: &KinematicState, dt: f32) -> KinematicState {
+        KinematicState {
+            position: state.position + state.velocity * dt,
+            velocity: state.velocity,
+            turn_rate: 0.0,
+        }
+    }
+
+    fn log_density(&self, from: &KinematicState, to: &KinematicState, dt: f32) -> f32 {
+        let predicted = self.predict(from, dt);
+        let root_dt = dt.sqrt();
+        log_gaussian(
+            distance2(&predicted.position, &to.position),
+            self.position_stddev * root_dt,
+            2.0,
+        ) + log_gaussian(
+            distance2(&predicted.velocity, &to.velocity),
+            self.velocity_stddev * root_dt,
+            2.0,
+        )
+    }
+}
+
+impl MotionModel for CoordinatedTurn {
+    fn propagate<R: Rng + ?Sized>(&self, state: &mut KinematicState, dt: f32, rng: &mut R) {
+        *state = self.predict(state, dt);
+        let root_dt = dt.sqrt();
+        state.position += gaussian_vec(self.position_stddev * root_dt, rng);
+        state.velocity += gaussian_vec(self.velocity_stddev * root_dt, rng);
+        state.turn_rate += gaussian(self.turn_rate_stddev * root_dt, rng);
+    }
+
+    fn predict(&self, state: &KinematicState, dt: f32) -> KinematicState {
+        let omega = state.turn_rate;
+        let (sin, cos) = (omega * dt).sin_cos();
+        let v = state.velocity;
+
+        // Integral of the rotating velocity, falling back to a straight line when
+        // the turn rate is close to zero
+        let displacement = if omega.abs() < 1e-4 {
+            v * dt
+        } else {
+            vec2(
+                (sin * v.x - (1.0 - cos) * v.y) / omega,
+                ((1.0 - cos) * v.x + sin * v.y) / omega,
+            )
+        };
+
+        KinematicState {
+            position: state.position + displacement,
+            velocity: vec2(cos * v.x - sin * v.y, sin * v.x + cos * v.y),
+            turn_rate: omega,
+        }
+    }
+
+    fn log_density(&self, from: &KinematicState, to: &KinematicState, dt: f32) -> f32 {
+        let predicted = self.predict(from, dt);
+        let root_dt = dt.sqrt();
+        let turn = to.turn_rate - predicted.turn_rate;
+        log_gaussian(
+            distance2(&predicted.position, &to.position),
+            self.position_stddev * root_dt,
+            2.0,
+        ) + log_gaussian(
+            distance2(&predicted.velocity, &to.velocity),
+            self.velocity_stddev * root_dt,
+            2.0,
+        ) + log_gaussian(turn * turn, self.turn_rate_stddev * root_dt, 1.0)
+    }
+}
+
+impl OrnsteinUhlenbeck {
+    // Stddev of the position after `dt` seconds of mean reversion
+    fn spread(&self, dt: f32) -> f32 {
+        if self.reversion <= 0.0 {
+            return self.stddev * dt.sqrt();
+        }
+        let variance = (1.0 - (-2.0 * self.reversion * dt).exp()) / (2.0 * self.reversion);
+        self.stddev * variance.sqrt()
+    }
+}
+
+impl MotionModel for OrnsteinUhlenbeck {
+    fn propagate<R: Rng + ?Sized>(&self, state: &mut KinematicState, dt: f32, rng: &mut R) {
+        *state = self.predict(state, dt);
+        state.position += gaussian_vec(self.spread(dt), rng);
+    }
+
+    // Velocity is the drift back towards home
+    fn predict(&self, state: &KinematicState, dt: f32) -> KinematicState {
+        let home = vec2(self.home[0], self.home[1]);
+        let decay = (-self.reversion * dt).exp();
+        KinematicState {
+            position: home + (state.position - home) * decay,
+            velocity: (home - state.position) * self.reversion,
+            turn_rate: 0.0,
+        }
+    }
+
+    fn log_density(&self, from: &KinematicState, to: &KinematicState, dt: f32) -> f32 {
+        let predicted = self.predict(from, dt);
+        log_gaussian(
+            distance2(&predicted.position, &to.position),
+            self.spread(dt),
+            2.0,
+        )
+    }
+}
+
+impl MotionModel for MotionKind {
+    fn propagate<R: Rng + ?Sized>(&self, state: &mut KinematicState, dt: f32, rng: &mut R) {
+        match self {
+            MotionKind::RandomWalk(model) => model.propagate(state, dt, rng),
+            MotionKind::ConstantVelocity(model) => model.propagate(state, dt, rng),
+            MotionKind::CoordinatedTurn(model) => model.propagate(state, dt, rng),
+            MotionKind::OrnsteinUhlenbeck(model) => model.propagate(state, dt, rng),
+        }
+    }
+
+    fn predict(&self, state: &KinematicState, dt: f32) -> KinematicState {
+        match self {
+            MotionKind::RandomWalk(model) => model.predict(state, dt),
+            MotionKind::ConstantVelocity(model) => model.predict(state, dt),
+            MotionKind::CoordinatedTurn(model) => model.predict(state, dt),
+            MotionKind::OrnsteinUhlenbeck(model) => model.predict(state, dt),
+        }
+    }
+
+    fn log_density(&self, from: &KinematicState, to: &KinematicState, dt: f32) -> f32 {
+        match self {
+            MotionKind::RandomWalk(model) => model.log_density(from, to, dt),
+            MotionKind::ConstantVelocity(model) => model.log_density(from, to, dt),
+            MotionKind::CoordinatedTurn(model) => model.log_density(from, to, dt),
+            MotionKind::OrnsteinUhlenbeck(model) => model.log_density(from, to, dt),
+        }
+    }
+}
@@ -0,0 +1,21 @@
+use rand::prng::XorShiftRng;
+use rand::{FromEntropy, SeedableRng};
+
+// Named generator so a seed reproduces the same run across platforms
+pub type SimRng = XorShiftRng;
+
+// Independent stream `index` of a run seeded with `seed`. Each tracker gets its own
+// stream so results don't depend on how rayon schedules them.
+pub fn stream(seed: u64, index: u64) -> SimRng {
+    SimRng::seed_from_u64(seed ^ index.wrapping_mul(0x9E37_79B9_7F4A_7C15))
+}
+
+// Generator seeded from `rng`, for draws that mustn't shift the numbers `rng` hands
+// out later
+pub fn split(rng: &mut SimRng) -> SimRng {
+    SimRng::from_rng(rng).unwrap()
+}
+
+pub fn from_entropy() -> SimRng {
+    SimRng::from_entropy()
+}
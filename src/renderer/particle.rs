@@ -1,3 +1,4 @@
+use filter::particle::ParticleState;
 use filter::Filter;
 use glium::backend::glutin::Display;
 use glium::{
@@ -6,7 +7,6 @@ use glium::{
     vertex::BufferCreationError,
     Program, Surface, VertexBuffer,
 };
-use glm::Vec2;
 use std::error::Error;
 use renderer::SimpleVertex;
 
@@ -24,11 +24,11 @@ impl ParticleRenderer {
         ParticleRenderer { particles, buffer }
     }
 
-    pub fn update_particles(&mut self, filter: &Filter<Vec2>) {
+    pub fn update_particles<T: ParticleState>(&mut self, filter: &Filter<T>) {
         self.particles.clear();
         for particle in filter.get_particles().iter() {
             self.particles.push(SimpleVertex {
-                position: *particle.as_ref(),
+                position: *particle.position().as_ref(),
             });
         }
     }
@@ -41,11 +41,16 @@ impl ParticleRenderer {
         self.particles.extend(particles);
     }
 
+    // Particle counts change as trackers adapt, so the buffer grows with some
+    // headroom rather than being rebuilt every time a few particles are added
     pub fn update_buffer(&mut self, display: &Display) -> Result<(), BufferCreationError> {
         self.buffer.invalidate();
         if self.buffer.len() < self.particles.len() {
-            self.buffer = VertexBuffer::dynamic(display, &self.particles)?;
-        } else {
+            let cap = self.particles.len().next_power_of_two();
+            self.buffer = VertexBuffer::empty_dynamic(display, cap)?;
+        }
+
+        if !self.particles.is_empty() {
             let slice = self.buffer.slice(0..self.particles.len()).unwrap();
             slice.write(&self.particles);
         }
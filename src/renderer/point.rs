@@ -36,6 +36,12 @@ impl ShapeRenderer {
         self.add_vertices(shape.iter().map(|x| *x));
     }
 
+    // A marker smaller than a box, for drawing paths
+    pub fn add_point(&mut self, position: Vec2, color: [f32; 3]) {
+        let shape = shape::build_rectangle(position, [0.08; 2], color);
+        self.add_vertices(shape.iter().map(|x| *x));
+    }
+
     pub fn update_buffer(&mut self, display: &Display) -> Result<(), BufferCreationError> {
         self.buffer.invalidate();
 
@@ -1,17 +1,187 @@
-use glm::Vec2;
+use glm::*;
+use measurement::MeasurementKind;
+use rand::distributions::{Distribution, Normal};
+use rand::Rng;
+use std::f32::consts::PI;
+use util::elipse_offset;
+
+// Most Lévy flights started in one update, so a degenerate flight length can't stall
+// the simulation
+const MAX_FLIGHTS_PER_UPDATE: usize = 64;
+
+// How a simulated animal moves. Speeds are in units per second and noise is per
+// square root second.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub enum AnimalMotion {
+    #[default]
+    Static,
+    // Orbits an ellipse with the given half extents, starting at its rightmost point
+    Ellipse {
+        vert: f32,
+        horiz: f32,
+        rate: f32,
+    },
+    // Walks between the points in order, back to the start position when `looped`
+    Waypoints {
+        points: Vec<[f32; 2]>,
+        speed: f32,
+        looped: bool,
+    },
+    RandomWalk {
+        stddev: f32,
+    },
+    // Constant speed with a heading that drifts by `turn_stddev`
+    CorrelatedRandomWalk {
+        speed: f32,
+        turn_stddev: f32,
+    },
+    // Straight flights in uniform directions with heavy tailed lengths. Lengths
+    // follow a Pareto distribution with exponent `alpha`, between `min_step` and
+    // `max_step`.
+    Levy {
+        alpha: f32,
+        min_step: f32,
+        max_step: f32,
+        speed: f32,
+    },
+}
 
 pub struct Animal {
     pub color: [f32; 3],
     pub position: Vec2,
-    pub signal_stddev: f32,
+    // What the drone measures of this animal
+    pub sensor: MeasurementKind,
+    pub motion: AnimalMotion,
+    // Seconds the animal has been moving for
+    elapsed: f32,
+    heading: f32,
+    // End of the current Lévy flight
+    target: Option<Vec2>,
+    waypoint: usize,
+    start: Vec2,
 }
 
 impl Animal {
-    pub fn new(position: impl Into<Vec2>, signal_stddev: f32, color: [f32; 3]) -> Animal {
+    pub fn new(
+        position: impl Into<Vec2>,
+        sensor: MeasurementKind,
+        color: [f32; 3],
+        motion: AnimalMotion,
+    ) -> Animal {
+        let position = position.into();
         Animal {
             color,
-            position: position.into(),
-            signal_stddev
+            position,
+            sensor,
+            motion,
+            elapsed: 0.0,
+            heading: 0.0,
+            target: None,
+            waypoint: 0,
+            start: position,
+        }
+    }
+
+    // Moves the animal forward by `dt` seconds
+    pub fn update<R: Rng + ?Sized>(&mut self, dt: f32, rng: &mut R) {
+        match self.motion.clone() {
+            AnimalMotion::Static => {}
+            // Computed from the elapsed time so the orbit doesn't drift
+            AnimalMotion::Ellipse { vert, horiz, rate } => {
+                self.position = self.start + elipse_offset(self.elapsed + dt, vert, horiz, rate);
+            }
+            AnimalMotion::Waypoints {
+                points,
+                speed,
+                looped,
+            } => self.follow_waypoints(&points, speed, looped, dt),
+            AnimalMotion::RandomWalk { stddev } => {
+                let normal = Normal::new(0.0, (stddev * dt.sqrt()) as f64);
+                self.position += vec2(normal.sample(rng) as f32, normal.sample(rng) as f32);
+            }
+            AnimalMotion::CorrelatedRandomWalk { speed, turn_stddev } => {
+                if turn_stddev > 0.0 {
+                    let normal = Normal::new(0.0, (turn_stddev * dt.sqrt()) as f64);
+                    self.heading += normal.sample(rng) as f32;
+                }
+                let (sin, cos) = self.heading.sin_cos();
+                self.position += vec2(cos, sin) * speed * dt;
+            }
+            AnimalMotion::Levy {
+                alpha,
+                min_step,
+                max_step,
+                speed,
+            } => {
+                let mut travel = speed * dt;
+                for _ in 0..MAX_FLIGHTS_PER_UPDATE {
+                    if travel <= 0.0 {
+                        break;
+                    }
+                    let target = match self.target {
+                        Some(target) => target,
+                        None => {
+                            let u = 1.0 - rng.gen::<f32>();
+                            let length = (min_step * u.powf(-1.0 / alpha)).min(max_step);
+                            let angle = rng.gen::<f32>() * 2.0 * PI;
+                            let (sin, cos) = angle.sin_cos();
+                            let target = self.position + vec2(cos, sin) * length;
+                            self.target = Some(target);
+                            target
+                        }
+                    };
+                    travel = self.move_towards(target, travel);
+                    if travel > 0.0 || self.position == target {
+                        self.target = None;
+                    }
+                }
+            }
+        }
+
+        self.elapsed += dt;
+    }
+
+    fn follow_waypoints(&mut self, points: &[[f32; 2]], speed: f32, looped: bool, dt: f32) {
+        // The start position is the first point of the loop
+        let len = points.len() + 1;
+        let start = self.start;
+        let point = |i: usize| {
+            if i == 0 {
+                start
+            } else {
+                vec2(points[i - 1][0], points[i - 1][1])
+            }
+        };
+
+        let mut travel = speed * dt;
+        // Bounded so a path of identical points can't spin forever
+        for _ in 0..len {
+            if travel <= 0.0 {
+                break;
+            }
+            if self.waypoint + 1 >= len && !looped {
+                break;
+            }
+
+            let next = (self.waypoint + 1) % len;
+            let target = point(next);
+            travel = self.move_towards(target, travel);
+            if self.position == target {
+                self.waypoint = next;
+            }
         }
     }
-}
\ No newline at end of file
+
+    // Moves up to `travel` towards `target`, returning the distance left over
+    fn move_towards(&mut self, target: Vec2, travel: f32) -> f32 {
+        let offset = target - self.position;
+        let dist = length(&offset);
+        if dist <= travel {
+            self.position = target;
+            travel - dist
+        } else {
+            self.position += offset * (travel / dist);
+            0.0
+        }
+    }
+}
@@ -1,16 +1,34 @@
+use association::Association;
+use detection::Detection;
+use filter::kld::KldConfig;
+use filter::mcmc::McmcConfig;
+use filter::regularize::Regularization;
+use filter::resample::{ResamplePolicy, ResamplingScheme};
+use filter::smoother::SmootherConfig;
+use glm::vec2;
+use measurement::{Likelihood, MeasurementKind, RangeModel};
+use motion::{MotionKind, RandomWalk};
 use serde_json;
+use state::animal::AnimalMotion;
+use state::MEASUREMENT_PERIOD;
 use std::error::Error;
 use std::fs::File;
 use std::io::BufReader;
 use std::ops::Range;
 use std::path::Path;
 use structopt::StructOpt;
+use telemetry::{OutOfSequence, TelemetryConfig};
+use track::TrackManagement;
+use tracker::FilterMode;
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "tracker")]
 pub struct CmdInput {
     #[structopt(short = "c", long = "config")]
     setup_config: String,
+    // Overrides the seed in the config file
+    #[structopt(short = "s", long = "seed")]
+    seed: Option<u64>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -19,6 +37,43 @@ pub struct StartBox {
     pub v_range: Range<f32>,
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AnimalConfig {
+    pub position: [f32; 2],
+    pub color: [f32; 3],
+    // Overrides `Config::filter_mode` for this animal's tracker
+    #[serde(default)]
+    pub filter_mode: Option<FilterMode>,
+    #[serde(default)]
+    pub motion: AnimalMotion,
+    // What the drone measures of this animal, `Config::sensor` when unset
+    #[serde(default)]
+    pub sensor: Option<MeasurementKind>,
+}
+
+impl AnimalConfig {
+    pub fn new(position: [f32; 2], color: [f32; 3]) -> AnimalConfig {
+        AnimalConfig {
+            position,
+            color,
+            filter_mode: None,
+            motion: AnimalMotion::Static,
+            sensor: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DroneConfig {
+    pub position: [f32; 2],
+    // Measurements taken per second
+    pub rate: f32,
+    // Measures every animal with this sensor, such as a noisier receiver, instead of
+    // the animal's own when set
+    #[serde(default)]
+    pub sensor: Option<MeasurementKind>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Config {
     pub particle_count: usize,
@@ -26,21 +81,247 @@ pub struct Config {
     pub stddev: f32,
     pub noise: f32,
     pub init_box: StartBox,
+    #[serde(default)]
     pub animals_pos: Vec<([f32; 2], [f32; 3])>,
+    // Animals with per animal settings, tracked alongside `animals_pos`
+    #[serde(default)]
+    pub animals: Vec<AnimalConfig>,
+    #[serde(default)]
+    pub resampler: ResamplingScheme,
+    // Resamples once the effective sample size halves when unset
+    #[serde(default = "default_resample_policy")]
+    pub resample_policy: ResamplePolicy,
+    // Adapts each tracker's particle count between the given bounds when set
+    #[serde(default)]
+    pub kld: Option<KldConfig>,
+    // Jitters particles with a kernel after resampling when set, independent of `noise`
+    #[serde(default)]
+    pub regularization: Option<Regularization>,
+    #[serde(default)]
+    pub filter_mode: FilterMode,
+    // Fraction of a lost tracker's particles drawn on the measured range ring, so it
+    // relocalises quickly. Trackers are lost after a reset or once their effective
+    // sample size collapses.
+    #[serde(default)]
+    pub annulus_fraction: f32,
+    // Records particle history for offline trajectory smoothing when set
+    #[serde(default)]
+    pub smoother: Option<SmootherConfig>,
+    // File a report on each track, such as its smoothed trajectory, and the track
+    // lifecycle events are written to as JSON when the window closes
+    #[serde(default)]
+    pub report: Option<String>,
+    // Resamples of particle ancestry kept for debugging path degeneracy when set
+    #[serde(default)]
+    pub genealogy_depth: Option<usize>,
+    // Runs a Metropolis-Hastings move after each resample when set. Bootstrap mode
+    // only, as the auxiliary filter doesn't resample after weighting.
+    #[serde(default)]
+    pub mcmc: Option<McmcConfig>,
+    // Seeds every random number generator so runs can be reproduced. A random seed
+    // is picked when unset.
+    #[serde(default)]
+    pub seed: Option<u64>,
+    // Process model of every tracker. Falls back to a random walk moving `noise` per
+    // measurement when unset.
+    #[serde(default)]
+    pub motion: Option<MotionKind>,
+    // What the drone measures of animals without their own sensor, such as RSSI from
+    // a radio collar. A range with `stddev` noise when unset.
+    #[serde(default)]
+    pub sensor: Option<MeasurementKind>,
+    // Error density of the fallback range sensor. The legacy p-value when unset so
+    // older configs reproduce, while sensors configured explicitly default to a
+    // gaussian.
+    #[serde(default)]
+    pub likelihood: Option<Likelihood>,
+    // Missed detections and clutter in the simulated receiver, matched by a robust
+    // likelihood in the trackers. Every animal is always heard cleanly when unset.
+    #[serde(default)]
+    pub detection: Option<Detection>,
+    // Positions of ground receivers used for TDOA measurements alongside the drone
+    #[serde(default)]
+    pub receivers: Vec<[f32; 2]>,
+    // The first drone follows the mouse. A single drone measuring once per
+    // `MEASUREMENT_PERIOD` when empty.
+    #[serde(default)]
+    pub drones: Vec<DroneConfig>,
+    // Delay between drones taking measurements and trackers receiving them
+    #[serde(default)]
+    pub telemetry: TelemetryConfig,
+    // How trackers handle measurements that arrive after newer ones
+    #[serde(default)]
+    pub out_of_sequence: OutOfSequence,
+    // Multi-target mode. Drones can't tell which animal they heard and detections are
+    // shared between trackers by data association.
+    #[serde(default)]
+    pub association: Option<Association>,
+    // Births, confirms and deletes tracks in multi-target mode when set, starting with
+    // none instead of one per configured animal
+    #[serde(default)]
+    pub tracks: Option<TrackManagement>,
+}
+
+fn default_resample_policy() -> ResamplePolicy {
+    ResamplePolicy::EssBelow(0.5)
 }
 
 impl Config {
     pub fn from_path(path: impl AsRef<Path>) -> Result<Config, Box<Error>> {
         let file = File::open(path)?;
         let reader = BufReader::new(file);
-        let config = serde_json::from_reader(reader)?;
+        let config: Config = serde_json::from_reader(reader)?;
+        config.validate()?;
 
         Ok(config)
     }
+
+    // Rejects settings the trackers or the simulation can't run with
+    fn validate(&self) -> Result<(), Box<Error>> {
+        if !(0.0..=1.0).contains(&self.annulus_fraction) {
+            return Err("annulus_fraction must be between 0 and 1".into());
+        }
+        for animal in self.animals.iter() {
+            if let AnimalMotion::Levy {
+                alpha,
+                min_step,
+                max_step,
+                ..
+            } = animal.motion
+            {
+                if !(alpha > 0.0 && min_step > 0.0 && min_step <= max_step) {
+                    return Err("Levy motion needs alpha > 0 and 0 < min_step <= max_step".into());
+                }
+            }
+        }
+
+        let auxiliary = |mode: FilterMode| matches!(mode, FilterMode::Auxiliary);
+        let any_auxiliary = auxiliary(self.filter_mode)
+            || self
+                .animals
+                .iter()
+                .filter_map(|x| x.filter_mode)
+                .any(auxiliary);
+        if self.mcmc.is_some() && any_auxiliary {
+            return Err("mcmc can't be used with the Auxiliary filter mode".into());
+        }
+
+        // Every sensor the drones can measure with
+        let sensors: Vec<MeasurementKind> = Some(self.default_sensor())
+            .into_iter()
+            .chain(self.animal_configs().iter().map(|x| self.sensor(x)))
+            .chain(self.drones.iter().filter_map(|x| self.drone_sensor(x)))
+            .collect();
+
+        let no_receivers = |sensor: &MeasurementKind| match sensor {
+            MeasurementKind::Tdoa(model) => model.receivers.is_empty(),
+            _ => false,
+        };
+        if sensors.iter().any(no_receivers) {
+            return Err("Tdoa sensors need at least one receiver".into());
+        }
+
+        // The p-value isn't a density, so it can't be mixed with the clutter density
+        let outliers = self.detection.is_some_and(|x| x.outlier_rate > 0.0);
+        let any_legacy = sensors
+            .iter()
+            .any(|x| matches!(x.likelihood(), Likelihood::LegacyPValue));
+        if outliers && any_legacy {
+            return Err("detection.outlier_rate can't be used with LegacyPValue".into());
+        }
+
+        if let Some(tracks) = self.tracks {
+            // Tracks are only born from detections shared by data association
+            if self.association.is_none() {
+                return Err("tracks needs association to be set".into());
+            }
+            if tracks.confirm_hits > tracks.confirm_window {
+                return Err("tracks.confirm_hits can't be more than confirm_window".into());
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn load() -> Result<Config, Box<Error>> {
         let input = CmdInput::from_args();
+        let mut config = Self::from_path(input.setup_config)?;
+        if input.seed.is_some() {
+            config.seed = input.seed;
+        }
+
+        Ok(config)
+    }
 
-        Self::from_path(input.setup_config)
+    pub fn animal_configs(&self) -> Vec<AnimalConfig> {
+        self.animals_pos
+            .iter()
+            .map(|(position, color)| AnimalConfig::new(*position, *color))
+            .chain(self.animals.iter().cloned())
+            .collect()
+    }
+
+    pub fn sensor(&self, animal: &AnimalConfig) -> MeasurementKind {
+        match animal.sensor {
+            Some(ref sensor) => self.with_receivers(sensor),
+            None => self.default_sensor(),
+        }
+    }
+
+    // Sensor of animals without their own
+    pub fn default_sensor(&self) -> MeasurementKind {
+        match self.sensor {
+            Some(ref sensor) => self.with_receivers(sensor),
+            None => MeasurementKind::Range(RangeModel {
+                stddev: self.stddev,
+                likelihood: self.likelihood.unwrap_or(Likelihood::LegacyPValue),
+            }),
+        }
+    }
+
+    pub fn drone_sensor(&self, drone: &DroneConfig) -> Option<MeasurementKind> {
+        drone
+            .sensor
+            .as_ref()
+            .map(|sensor| self.with_receivers(sensor))
+    }
+
+    // Copies the sensor, pointing TDOA sensors at the configured receivers
+    fn with_receivers(&self, sensor: &MeasurementKind) -> MeasurementKind {
+        let mut sensor = sensor.clone();
+        if let MeasurementKind::Tdoa(ref mut model) = sensor {
+            model.receivers = self.receivers.iter().map(|&[x, y]| vec2(x, y)).collect();
+        }
+
+        sensor
+    }
+
+    pub fn drone_configs(&self) -> Vec<DroneConfig> {
+        if !self.drones.is_empty() {
+            return self.drones.clone();
+        }
+
+        vec![DroneConfig {
+            position: [0.0; 2],
+            rate: 1.0 / MEASUREMENT_PERIOD,
+            sensor: None,
+        }]
+    }
+
+    pub fn motion_model(&self) -> MotionKind {
+        self.motion.unwrap_or_else(|| {
+            MotionKind::RandomWalk(RandomWalk {
+                stddev: self.noise / MEASUREMENT_PERIOD.sqrt(),
+            })
+        })
+    }
+
+    // Most particles a single tracker can hold
+    pub fn max_particles(&self) -> usize {
+        match self.kld {
+            Some(kld) => kld.max_particles.max(self.particle_count),
+            None => self.particle_count,
+        }
     }
 }
 
@@ -50,12 +331,121 @@ impl Default for Config {
             particle_count: 500,
             particle_color: [1.0, 0.0, 0.0, 0.3],
             animals_pos: vec![([0.0; 2], [1.0; 3])],
+            animals: vec![],
             stddev: 0.5,
             noise: 0.2,
             init_box: StartBox {
                 h_range: -10.0..10.0,
                 v_range: -10.0..10.0,
             },
+            resampler: ResamplingScheme::Systematic,
+            resample_policy: default_resample_policy(),
+            kld: None,
+            regularization: None,
+            filter_mode: FilterMode::Bootstrap,
+            annulus_fraction: 0.0,
+            smoother: None,
+            report: None,
+            genealogy_depth: None,
+            mcmc: None,
+            seed: None,
+            motion: None,
+            sensor: None,
+            likelihood: None,
+            detection: None,
+            receivers: vec![],
+            drones: vec![],
+            telemetry: TelemetryConfig::default(),
+            out_of_sequence: OutOfSequence::Drop,
+            association: None,
+            tracks: None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use measurement::TdoaModel;
+
+    #[test]
+    fn files_without_a_resample_policy_match_the_default() {
+        let json = r#"{
+            "particle_count": 100,
+            "particle_color": [1.0, 0.0, 0.0, 0.3],
+            "stddev": 0.5,
+            "noise": 0.2,
+            "init_box": {
+                "h_range": {"start": -10.0, "end": 10.0},
+                "v_range": {"start": -10.0, "end": 10.0}
+            }
+        }"#;
+        let config: Config = serde_json::from_str(json).unwrap();
+
+        let ess_below = |config: &Config| match config.resample_policy {
+            ResamplePolicy::EssBelow(fraction) => Some(fraction),
+            _ => None,
+        };
+        assert_eq!(ess_below(&config), Some(0.5));
+        assert_eq!(ess_below(&config), ess_below(&Config::default()));
+    }
+
+    #[test]
+    fn mcmc_is_rejected_in_auxiliary_mode() {
+        let mut config = Config {
+            mcmc: Some(McmcConfig::default()),
+            ..Config::default()
+        };
+        assert!(config.validate().is_ok());
+
+        let mut animal = AnimalConfig::new([0.0; 2], [1.0; 3]);
+        animal.filter_mode = Some(FilterMode::Auxiliary);
+        config.animals.push(animal);
+        assert!(config.validate().is_err());
+
+        config.animals.clear();
+        config.filter_mode = FilterMode::Auxiliary;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn tdoa_sensors_need_a_receiver() {
+        let tdoa = TdoaModel {
+            stddev: 0.5,
+            likelihood: Likelihood::Gaussian,
+            receivers: vec![],
+        };
+        let mut config = Config {
+            sensor: Some(MeasurementKind::Tdoa(tdoa)),
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+
+        config.receivers = vec![[5.0, 5.0]];
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn tracks_need_association_and_a_reachable_confirmation() {
+        let tracks = TrackManagement {
+            confirm_hits: 3,
+            confirm_window: 5,
+            timeout: 10.0,
+            max_area: 50.0,
+        };
+        let mut config = Config {
+            tracks: Some(tracks),
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+
+        config.association = Some(Association::NearestNeighbour { gate: -10.0 });
+        assert!(config.validate().is_ok());
+
+        config.tracks = Some(TrackManagement {
+            confirm_hits: 6,
+            ..tracks
+        });
+        assert!(config.validate().is_err());
+    }
+}
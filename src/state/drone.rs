@@ -0,0 +1,36 @@
+use glm::Vec2;
+use measurement::MeasurementKind;
+
+pub struct Drone {
+    pub position: Vec2,
+    // Measures every animal with this sensor instead of the animal's own when set
+    pub sensor: Option<MeasurementKind>,
+    // Measurements taken per second
+    pub rate: f32,
+    // Fraction of the next measurement built up so far
+    pending: f32,
+}
+
+impl Drone {
+    pub fn new(position: impl Into<Vec2>, sensor: Option<MeasurementKind>, rate: f32) -> Drone {
+        Drone {
+            position: position.into(),
+            sensor,
+            rate,
+            pending: 0.0,
+        }
+    }
+
+    // Advances the drone's clock by `dt` seconds and returns when it takes each
+    // measurement in that time, in seconds since the start of the step
+    pub fn measurement_times(&mut self, dt: f32) -> Vec<f32> {
+        let start = self.pending;
+        self.pending += self.rate * dt;
+        let due = self.pending.floor().max(0.0);
+        self.pending -= due;
+
+        (0..due as usize)
+            .map(|k| ((k + 1) as f32 - start) / self.rate)
+            .collect()
+    }
+}
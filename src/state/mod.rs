@@ -1,89 +1,351 @@
+use association::{Association, Scan};
+use detection::{Detection, Observation};
+use filter::particle::ParticleState;
 use glium::backend::glutin::Display;
-use glm::{vec2, Vec2};
-use measurement;
+use measurement::MeasurementKind;
+use rand;
+use random::{self, SimRng};
 use rayon::prelude::*;
 use renderer::SimpleVertex;
+use serde_json;
 use std::error::Error;
-use std::mem;
+use std::fs::File;
+use telemetry::Telemetry;
+use track::{Track, TrackEvent, TrackManagement};
 use tracker::AnimalTracker;
 
 pub mod animal;
 pub mod config;
+pub mod drone;
 pub mod render;
 
+// Seconds between measurements of the default drone
+pub const MEASUREMENT_PERIOD: f32 = 0.25;
+
+// Written to `Config::report` when the run ends
+#[derive(Serialize)]
+pub struct Report {
+    pub tracks: Vec<TrackReport>,
+    pub events: Vec<TrackEvent>,
+}
+
+// What the report says about one track
+#[derive(Serialize)]
+pub struct TrackReport {
+    pub id: usize,
+    // Smoothed position at each recorded step, oldest first. Empty unless smoothing
+    // is enabled.
+    pub smoothed: Vec<[f32; 2]>,
+    // Distinct ancestors of the particles one, two and more resamples back, and how
+    // far back they all share one. Empty and None unless genealogy is recorded.
+    pub unique_ancestors: Vec<usize>,
+    pub coalescence_time: Option<usize>,
+}
+
 pub struct State {
-    pub drone_pos: Vec2,
-    pub trackers: Vec<(AnimalTracker, usize)>,
+    pub drones: Vec<drone::Drone>,
+    pub trackers: Vec<(AnimalTracker, Track)>,
     pub renderer: render::StateRenderer,
     pub animals: Vec<animal::Animal>,
+    // Drives the simulation. Each tracker's filter has its own stream.
+    pub rng: SimRng,
+    pub detection: Detection,
+    // Observations of a known animal, for the tracker at the given index
+    pub telemetry: Telemetry<(usize, Observation)>,
+    // Shares anonymous detections between trackers when set, instead of each
+    // measurement being known to come from its tracker's animal
+    pub association: Option<Association>,
+    pub scans: Telemetry<Scan>,
+    // Sensor every drone without its own uses on anonymous detections
+    pub sensor: MeasurementKind,
+    // Seconds since the simulation started
+    pub time: f64,
+    // Track lifecycle in multi-target mode, and every change to it since the run
+    // started, oldest first. The events are drained into the report.
+    pub tracks: Option<TrackManagement>,
+    pub events: Vec<TrackEvent>,
+    // Kept to build trackers born from detections
+    config: config::Config,
+    seed: u64,
+    next_id: usize,
 }
 
 impl State {
     pub fn new(display: &Display) -> Result<State, Box<Error>> {
         let config = config::Config::load()?;
-        let renderer =
-            render::StateRenderer::new(display, config.particle_count, 12, config.particle_color);
+        let animal_configs = config.animal_configs();
+        let renderer = render::StateRenderer::new(
+            display,
+            config.max_particles() * animal_configs.len(),
+            12,
+            config.particle_color,
+        );
+        let seed = config.seed.unwrap_or_else(rand::random);
         let mut animals = vec![];
         let mut trackers = vec![];
 
-        for (i, animal) in config.animals_pos.iter().enumerate() {
-            animals.push(animal::Animal::new(animal.0, config.stddev, animal.1));
-            trackers.push((
-                AnimalTracker::new(
-                    config.particle_count,
-                    config.init_box.h_range.clone(),
-                    config.init_box.v_range.clone(),
-                    config.noise,
-                ),
-                i,
+        let drones = config
+            .drone_configs()
+            .iter()
+            .map(|drone| drone::Drone::new(drone.position, config.drone_sensor(drone), drone.rate))
+            .collect();
+
+        // Managed tracks are only born from detections
+        let managed = config.association.is_some() && config.tracks.is_some();
+        for (i, animal) in animal_configs.iter().enumerate() {
+            animals.push(animal::Animal::new(
+                animal.position,
+                config.sensor(animal),
+                animal.color,
+                animal.motion.clone(),
             ));
+            if !managed {
+                trackers.push((
+                    AnimalTracker::from_config(&config, animal, random::stream(seed, i as u64 + 1)),
+                    Track::animal(i, i, animal.color, 0.0),
+                ));
+            }
         }
 
         Ok(State {
-            drone_pos: vec2(0.0, 0.0),
+            drones,
             trackers,
             renderer,
             animals,
+            rng: random::stream(seed, 0),
+            detection: config.detection.unwrap_or_default(),
+            telemetry: Telemetry::new(config.telemetry),
+            association: config.association,
+            scans: Telemetry::new(config.telemetry),
+            sensor: config.default_sensor(),
+            time: 0.0,
+            tracks: if managed { config.tracks } else { None },
+            events: vec![],
+            next_id: animal_configs.len(),
+            config,
+            seed,
         })
     }
 
-    pub fn update(&mut self, time: f32) {
-        let drone_pos = self.drone_pos;
-        let animals = mem::replace(&mut self.animals, vec![]);
+    // Moves the simulation forward by `dt` seconds. Each drone measures every animal
+    // as many times as its rate allows, and trackers take whatever measurements have
+    // arrived over telemetry.
+    pub fn update(&mut self, dt: f32) {
+        let start = self.time;
+        self.time += dt as f64;
+        let rng = &mut self.rng;
+        for animal in self.animals.iter_mut() {
+            animal.update(dt, rng);
+        }
+        let due: Vec<Vec<f64>> = self
+            .drones
+            .iter_mut()
+            .map(|drone| {
+                let times = drone.measurement_times(dt);
+                times.into_iter().map(|x| start + x as f64).collect()
+            })
+            .collect();
+
+        match self.association {
+            Some(_) => self.scan(&due),
+            None => self.measure(&due),
+        }
+
+        let now = self.time;
+        let mut received = vec![vec![]; self.trackers.len()];
+        for (i, observation) in self.telemetry.receive(now) {
+            received[i].push(observation);
+        }
+        if let Some(association) = self.association {
+            for scan in self.scans.receive(now) {
+                let (observations, unassociated) = association.associate(&scan, &self.trackers);
+                for (received, observation) in received.iter_mut().zip(observations) {
+                    received.push(observation);
+                }
+                if self.tracks.is_some() {
+                    for j in unassociated {
+                        self.birth(&scan, j);
+                        received.push(vec![]);
+                    }
+                }
+            }
+        }
+
+        if let Some(management) = self.tracks {
+            for ((_, track), observations) in self.trackers.iter_mut().zip(received.iter()) {
+                let events = &mut self.events;
+                events.extend(
+                    observations
+                        .iter()
+                        .filter_map(|x| track.observe(x, &management)),
+                );
+            }
+        }
+
+        self.trackers
+            .par_iter_mut()
+            .zip(received.into_par_iter())
+            .for_each(|((tracker, _), observations)| {
+                tracker.receive(observations, now);
+            });
+
+        if let Some(management) = self.tracks {
+            self.delete_lost(&management);
+        }
+    }
+
+    // Starts a tentative track from detection `j` of `scan`
+    fn birth(&mut self, scan: &Scan, j: usize) {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let rng = random::stream(self.seed, id as u64 + 1);
+        let measurement = scan.detections[j].clone();
+        let observation = Observation::new(
+            scan.time,
+            scan.drone,
+            scan.sensor.clone(),
+            Some(measurement),
+        );
+        let tracker = AnimalTracker::born(&self.config, observation, rng);
 
-        self.trackers.par_iter_mut().for_each(|(tracker, id)| {
-            let animal = &animals[*id];
-            let measurement =
-                measurement::generate_measurement(drone_pos, animal.position, animal.signal_stddev);
-            tracker.update_filter(drone_pos, measurement, animal.signal_stddev, time);
+        self.trackers.push((tracker, Track::born(id, scan.time)));
+        self.events.push(TrackEvent::Born {
+            id,
+            time: scan.time,
         });
+    }
+
+    fn delete_lost(&mut self, management: &TrackManagement) {
+        let now = self.time;
+        let events = &mut self.events;
+        self.trackers.retain(
+            |(tracker, track)| match track.deletion(tracker, now, management) {
+                Some(reason) => {
+                    events.push(TrackEvent::Deleted {
+                        id: track.id,
+                        time: now,
+                        reason,
+                    });
+                    false
+                }
+                None => true,
+            },
+        );
+    }
+
+    // Measures each tracker's animal with drone i at each of the times in `due[i]`
+    fn measure(&mut self, due: &[Vec<f64>]) {
+        let rng = &mut self.rng;
+        let detection = &self.detection;
+
+        // Measurements are drawn in tracker order so they don't depend on scheduling
+        for (i, (_, track)) in self.trackers.iter().enumerate() {
+            let animal = match track.animal {
+                Some(animal) => &self.animals[animal],
+                None => continue,
+            };
+            for (drone, times) in self.drones.iter().zip(due.iter()) {
+                let sensor = drone.sensor.as_ref().unwrap_or(&animal.sensor);
+                for &time in times.iter() {
+                    let measurement =
+                        detection.observe(sensor, drone.position, animal.position, rng);
+                    let observation =
+                        Observation::new(time, drone.position, sensor.clone(), measurement);
+                    self.telemetry.send(time, (i, observation), rng);
+                }
+            }
+        }
+    }
 
-        mem::replace(&mut self.animals, animals);
+    // Listens for every animal with drone i at each of the times in `due[i]`, without
+    // recording which animal each detection came from
+    fn scan(&mut self, due: &[Vec<f64>]) {
+        let rng = &mut self.rng;
+        let detection = &self.detection;
 
+        for (drone, times) in self.drones.iter().zip(due.iter()) {
+            let sensor = drone.sensor.as_ref().unwrap_or(&self.sensor);
+            for &time in times.iter() {
+                let detections = self
+                    .animals
+                    .iter()
+                    .filter_map(|animal| {
+                        detection.observe(sensor, drone.position, animal.position, rng)
+                    })
+                    .collect();
+                let scan = Scan {
+                    time,
+                    drone: drone.position,
+                    sensor: sensor.clone(),
+                    detections,
+                };
+                self.scans.send(time, scan, rng);
+            }
+        }
+    }
+
+    // Writes the track events and a report on every track still running to
+    // `Config::report`, if set
+    pub fn save_report(&mut self) -> Result<(), Box<Error>> {
+        let path = match self.config.report {
+            Some(ref path) => path.clone(),
+            None => return Ok(()),
+        };
+        let tracks = self
+            .trackers
+            .iter_mut()
+            .map(|(tracker, track)| TrackReport {
+                id: track.id,
+                smoothed: tracker
+                    .smoothed_trajectory()
+                    .iter()
+                    .map(|x| *x.as_ref())
+                    .collect(),
+                unique_ancestors: tracker.unique_ancestors(),
+                coalescence_time: tracker.coalescence_time(),
+            })
+            .collect();
+        let report = Report {
+            tracks,
+            events: self.events.drain(..).collect(),
+        };
+
+        let file = File::create(path)?;
+        serde_json::to_writer(file, &report)?;
+        Ok(())
     }
 
     pub fn update_renderer(&mut self, display: &Display) {
         self.renderer.particle_renderer.clear_particles();
         self.renderer.point_renderer.clear_shapes();
 
-        for (i, tracker) in self.trackers.iter_mut().enumerate() {
-            let animal = &self.animals[i];
+        for (tracker, track) in self.trackers.iter() {
             self.renderer
                 .particle_renderer
-                .add_particles(tracker.0.filter.particles.iter().map(|x| SimpleVertex {
-                    position: *x.as_ref(),
+                .add_particles(tracker.filter.particles.iter().map(|x| SimpleVertex {
+                    position: *x.position().as_ref(),
                 }));
 
             self.renderer
                 .point_renderer
-                .add_box(tracker.0.est_pos, animal.color);
+                .add_box(tracker.est_pos, track.color);
+            for &position in tracker.lineage() {
+                self.renderer
+                    .point_renderer
+                    .add_point(position, track.color);
+            }
+        }
+        for animal in self.animals.iter() {
             self.renderer
                 .point_renderer
                 .add_box(animal.position, animal.color);
         }
-        self.renderer
-            .point_renderer
-            .add_box(self.drone_pos, [0.0, 0.0, 1.0]);
+        for drone in self.drones.iter() {
+            self.renderer
+                .point_renderer
+                .add_box(drone.position, [0.0, 0.0, 1.0]);
+        }
 
         self.renderer
             .particle_renderer
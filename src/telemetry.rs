@@ -0,0 +1,68 @@
+use rand::Rng;
+
+// Delay between a drone taking a measurement and it reaching the trackers. Each
+// report is delayed by `latency` plus a uniform draw of up to `jitter` seconds, so
+// reports can arrive out of order.
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TelemetryConfig {
+    pub latency: f32,
+    pub jitter: f32,
+}
+
+// What a tracker does with an observation older than the time its particles are at
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub enum OutOfSequence {
+    #[default]
+    Drop,
+    // Holds observations back for `lag` seconds and applies them in time order.
+    // Observations later than that are dropped.
+    Buffer { lag: f32 },
+    // Restores the filter from before the late observation and replays everything
+    // since. `history` is the number of updates that can be rewound.
+    Rewind { history: usize },
+}
+
+struct Report<T> {
+    arrival: f64,
+    payload: T,
+}
+
+// Reports in flight from the drones
+pub struct Telemetry<T> {
+    pub config: TelemetryConfig,
+    in_flight: Vec<Report<T>>,
+}
+
+impl<T> Telemetry<T> {
+    pub fn new(config: TelemetryConfig) -> Telemetry<T> {
+        Telemetry {
+            config,
+            in_flight: vec![],
+        }
+    }
+
+    // Sends a report taken at `time`
+    pub fn send<R: Rng + ?Sized>(&mut self, time: f64, payload: T, rng: &mut R) {
+        let delay = self.config.latency + rng.gen::<f32>() * self.config.jitter;
+        self.in_flight.push(Report {
+            arrival: time + delay as f64,
+            payload,
+        });
+    }
+
+    // Reports that have arrived by `now`, in arrival order
+    pub fn receive(&mut self, now: f64) -> Vec<T> {
+        let mut arrived = vec![];
+        let mut i = 0;
+        while i < self.in_flight.len() {
+            if self.in_flight[i].arrival <= now {
+                arrived.push(self.in_flight.swap_remove(i));
+            } else {
+                i += 1;
+            }
+        }
+        arrived.sort_by(|a, b| a.arrival.partial_cmp(&b.arrival).unwrap());
+
+        arrived.into_iter().map(|x| x.payload).collect()
+    }
+}
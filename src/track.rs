@@ -0,0 +1,156 @@
+use detection::Observation;
+use std::collections::VecDeque;
+use tracker::AnimalTracker;
+
+// Lifecycle of trackers in multi-target mode. Tracks are born tentative from
+// detections no tracker explains, confirmed once they keep hearing their animal and
+// deleted once they lose it.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct TrackManagement {
+    // A tentative track is confirmed once its animal is heard in `confirm_hits` of its
+    // last `confirm_window` observations, and deleted if that can no longer happen
+    pub confirm_hits: usize,
+    pub confirm_window: usize,
+    // Seconds without hearing its animal before a track is deleted
+    pub timeout: f32,
+    // Tracks whose 95% credible region grows larger than this area are deleted
+    pub max_area: f32,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TrackStatus {
+    Tentative,
+    Confirmed,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum DeletionReason {
+    // Too few hits within the confirmation window
+    Unconfirmed,
+    Timeout,
+    // The credible region grew past `TrackManagement::max_area`
+    Diverged,
+}
+
+// A change to the set of tracks, at `time` seconds into the simulation
+#[derive(Copy, Clone, Debug, Serialize)]
+pub enum TrackEvent {
+    Born {
+        id: usize,
+        time: f64,
+    },
+    Confirmed {
+        id: usize,
+        time: f64,
+    },
+    Deleted {
+        id: usize,
+        time: f64,
+        reason: DeletionReason,
+    },
+}
+
+// Bookkeeping kept alongside each tracker
+#[derive(Clone, Debug)]
+pub struct Track {
+    pub id: usize,
+    // The animal this track is known to follow, None for tracks born from detections
+    pub animal: Option<usize>,
+    pub color: [f32; 3],
+    pub status: TrackStatus,
+    // Whether the animal was heard in each recent observation, newest last
+    hits: VecDeque<bool>,
+    last_hit: f64,
+}
+
+impl Track {
+    // A track following a configured animal from `time`, confirmed from the start
+    pub fn animal(id: usize, animal: usize, color: [f32; 3], time: f64) -> Track {
+        Track {
+            id,
+            animal: Some(animal),
+            color,
+            status: TrackStatus::Confirmed,
+            hits: VecDeque::new(),
+            last_hit: time,
+        }
+    }
+
+    // A tentative track born from a detection heard at `time`, which counts as its
+    // first hit
+    pub fn born(id: usize, time: f64) -> Track {
+        Track {
+            id,
+            animal: None,
+            color: track_color(id),
+            status: TrackStatus::Tentative,
+            hits: VecDeque::from(vec![true]),
+            last_hit: time,
+        }
+    }
+
+    // Counts an observation given to the track's tracker. Returns the confirmation
+    // event if this confirms the track.
+    pub fn observe(
+        &mut self,
+        observation: &Observation,
+        management: &TrackManagement,
+    ) -> Option<TrackEvent> {
+        let hit = observation.measurement().is_some();
+        if hit {
+            self.last_hit = self.last_hit.max(observation.time);
+        }
+        self.hits.push_back(hit);
+        while self.hits.len() > management.confirm_window {
+            self.hits.pop_front();
+        }
+
+        if self.status == TrackStatus::Tentative && self.hit_count() >= management.confirm_hits {
+            self.status = TrackStatus::Confirmed;
+            return Some(TrackEvent::Confirmed {
+                id: self.id,
+                time: observation.time,
+            });
+        }
+
+        None
+    }
+
+    // Why the track should be deleted at `now`, None if it should be kept
+    pub fn deletion(
+        &self,
+        tracker: &AnimalTracker,
+        now: f64,
+        management: &TrackManagement,
+    ) -> Option<DeletionReason> {
+        let remaining = management.confirm_window - self.hits.len().min(management.confirm_window);
+        if self.status == TrackStatus::Tentative
+            && self.hit_count() + remaining < management.confirm_hits
+        {
+            Some(DeletionReason::Unconfirmed)
+        } else if now - self.last_hit > management.timeout as f64 {
+            Some(DeletionReason::Timeout)
+        } else if tracker.estimate.credible.area() > management.max_area {
+            Some(DeletionReason::Diverged)
+        } else {
+            None
+        }
+    }
+
+    fn hit_count(&self) -> usize {
+        self.hits.iter().filter(|&&hit| hit).count()
+    }
+}
+
+// Colours tracks born from detections so neighbouring ids are easy to tell apart
+fn track_color(id: usize) -> [f32; 3] {
+    const PALETTE: [[f32; 3]; 6] = [
+        [1.0, 0.5, 0.0],
+        [0.0, 0.8, 0.8],
+        [0.8, 0.0, 0.8],
+        [0.5, 1.0, 0.0],
+        [1.0, 1.0, 0.0],
+        [0.5, 0.5, 1.0],
+    ];
+    PALETTE[id % PALETTE.len()]
+}
@@ -1,13 +1,103 @@
+use detection::{log_sum_exp, Detection, Observation};
+use filter::estimate::Estimate;
+use filter::mcmc::McmcConfig;
+use filter::particle::KinematicState;
+use filter::particle::ParticleState;
+use filter::smoother::Smoother;
 use filter::Filter;
 use glm::*;
-use measurement::weighting;
-use std::mem;
+use measurement::MeasurementModel;
+use motion::{MotionKind, MotionModel};
+use random::{self, SimRng};
+use state::config::{AnimalConfig, Config};
+use std::collections::VecDeque;
+use std::f32;
 use std::ops::Range;
+use telemetry::OutOfSequence;
+
+// Cell size used to cluster particles when looking for the mode
+const MODE_CELL_SIZE: f32 = 0.5;
+// A tracker whose effective sample size falls below this fraction of its particles
+// is treated as lost and relocalised from the next measurement
+const LOST_ESS_FRACTION: f32 = 0.1;
+
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub enum FilterMode {
+    // Propagate, then weight by the measurement
+    #[default]
+    Bootstrap,
+    // Resample by the likelihood of a point prediction before propagating, which
+    // keeps particles near the measurement annulus
+    Auxiliary,
+}
+
+// The tracker as it was before an update, kept so late observations can be replayed
+struct Snapshot {
+    time: f64,
+    filter: Filter<KinematicState>,
+    recorded: usize,
+    // Observations in `applied` from before the snapshot, the rest are replayed
+    applied: usize,
+}
+
+// Splits observations into groups taken at the same time, oldest first
+fn time_groups(mut observations: Vec<Observation>) -> Vec<Vec<Observation>> {
+    observations.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
+
+    let mut groups: Vec<Vec<Observation>> = vec![];
+    for observation in observations {
+        let same_time = groups
+            .last()
+            .is_some_and(|group| group[0].time == observation.time);
+        if same_time {
+            groups.last_mut().unwrap().push(observation);
+        } else {
+            groups.push(vec![observation]);
+        }
+    }
+
+    groups
+}
 
 pub struct AnimalTracker {
     pub est_pos: Vec2,
-    pub filter: Filter<Vec2>,
-    pub noise: f32,
+    pub est_vel: Vec2,
+    pub estimate: Estimate<KinematicState>,
+    pub filter: Filter<KinematicState>,
+    pub motion: MotionKind,
+    pub mode: FilterMode,
+    // Fraction of particles redrawn where the measurement places the animal once the
+    // tracker is lost
+    pub annulus_fraction: f32,
+    // Keeps past particle sets for offline trajectory smoothing when set
+    pub smoother: Option<Smoother<KinematicState>>,
+    // Drives backward sampling, apart from the filter's stream so looking at the
+    // smoothed trajectory doesn't change the filter's run
+    smoother_rng: SimRng,
+    // Metropolis-Hastings moves run after each resample when set (bootstrap mode only)
+    pub mcmc: Option<McmcConfig>,
+    // Chance of missing the animal or hearing clutter, used to make the likelihood
+    // robust to outliers
+    pub detection: Detection,
+    // Time of the last observation applied, in seconds into the simulation
+    pub time: f64,
+    pub out_of_sequence: OutOfSequence,
+    // Observations thrown away for arriving too late to be applied
+    pub late_dropped: usize,
+    // Observations held back by `OutOfSequence::Buffer`
+    pending: Vec<Observation>,
+    // Snapshots and the observations applied since the oldest of them, kept by
+    // `OutOfSequence::Rewind`
+    history: VecDeque<Snapshot>,
+    applied: Vec<Observation>,
+    // Positions the highest weighted particle at the last estimate descends from
+    lineage: Vec<Vec2>,
+    // Steps recorded to the smoother so far
+    recorded: usize,
+    // Set after a reset or a collapse in effective sample size
+    lost: bool,
+    h_range: Range<f32>,
+    v_range: Range<f32>,
 }
 
 impl AnimalTracker {
@@ -15,28 +105,514 @@ impl AnimalTracker {
         particles: usize,
         h_range: Range<f32>,
         v_range: Range<f32>,
-        noise: f32,
+        motion: MotionKind,
+        mut rng: SimRng,
     ) -> AnimalTracker {
-        let filter = Filter::new_start_box(particles, h_range, v_range);
-        let est_pos = filter.guess_position();
+        let smoother_rng = random::split(&mut rng);
+        let filter: Filter<KinematicState> =
+            Filter::new_start_box(particles, h_range.clone(), v_range.clone(), rng);
+        let estimate = filter.estimate(MODE_CELL_SIZE);
 
         AnimalTracker {
-            est_pos,
+            est_pos: estimate.mean.position,
+            est_vel: estimate.mean.velocity,
+            estimate,
             filter,
-            noise,
+            motion,
+            mode: FilterMode::default(),
+            annulus_fraction: 0.0,
+            smoother: None,
+            smoother_rng,
+            mcmc: None,
+            detection: Detection::default(),
+            time: 0.0,
+            out_of_sequence: OutOfSequence::default(),
+            late_dropped: 0,
+            pending: vec![],
+            history: VecDeque::new(),
+            applied: vec![],
+            lineage: vec![],
+            recorded: 0,
+            lost: false,
+            h_range,
+            v_range,
         }
     }
 
-    pub fn update_filter(&mut self, drone_pos: Vec2, measurement: f32, stddev: f32, time: f32) {
-        let mut filter = mem::replace(&mut self.filter, Filter::new());
-        filter.reweight(|value| weighting(drone_pos, *value, measurement, stddev));
-        filter.normalize_weights();
-        filter.set_sampling_weights();
-        filter.resample();
-        // filter.transition(|particle| *particle += self.est_vel * time);
-        filter.random_movement(self.noise);
+    pub fn from_config(config: &Config, animal: &AnimalConfig, rng: SimRng) -> AnimalTracker {
+        let mode = animal.filter_mode.unwrap_or(config.filter_mode);
+        AnimalTracker::configured(config, mode, rng)
+    }
+
+    // A tracker started from the detection it was born from, with every particle
+    // drawn where `observation` puts the animal
+    pub fn born(config: &Config, observation: Observation, rng: SimRng) -> AnimalTracker {
+        let mut tracker = AnimalTracker::configured(config, config.filter_mode, rng);
+        tracker.time = observation.time;
+        tracker.inject_measured(&observation, 1.0);
+        tracker.step(vec![observation]);
+
+        tracker
+    }
+
+    fn configured(config: &Config, mode: FilterMode, rng: SimRng) -> AnimalTracker {
+        let mut tracker = AnimalTracker::new(
+            config.particle_count,
+            config.init_box.h_range.clone(),
+            config.init_box.v_range.clone(),
+            config.motion_model(),
+            rng,
+        );
+        tracker.filter.set_resampler(config.resampler);
+        tracker.filter.set_policy(config.resample_policy);
+        tracker.filter.set_kld(config.kld);
+        tracker.filter.set_regularization(config.regularization);
+        tracker.filter.set_genealogy(config.genealogy_depth);
+        tracker.mode = mode;
+        tracker.annulus_fraction = config.annulus_fraction;
+        tracker.smoother = config.smoother.map(Smoother::new);
+        tracker.mcmc = config.mcmc;
+        tracker.detection = config.detection.unwrap_or_default();
+        tracker.out_of_sequence = config.out_of_sequence;
+
+        tracker
+    }
+
+    // Takes the observations that have arrived by `now`, in any order
+    pub fn receive(&mut self, observations: Vec<Observation>, now: f64) {
+        let observations = match self.out_of_sequence {
+            OutOfSequence::Buffer { lag } => {
+                self.pending.extend(observations);
+                let ready = now - lag as f64;
+                let (ready, waiting) = self.pending.drain(..).partition(|x| x.time <= ready);
+                self.pending = waiting;
+                ready
+            }
+            _ => observations,
+        };
+
+        for group in time_groups(observations) {
+            if group[0].time >= self.time {
+                self.step(group);
+            } else if let OutOfSequence::Rewind { .. } = self.out_of_sequence {
+                self.rewind(group);
+            } else {
+                self.late_dropped += group.len();
+            }
+        }
+    }
+
+    // Predicts to the time of `observations`, which were all taken together, and
+    // updates with them
+    fn step(&mut self, observations: Vec<Observation>) {
+        let time = observations[0].time;
+        if let OutOfSequence::Rewind { history } = self.out_of_sequence {
+            if history > 0 {
+                if self.history.len() >= history {
+                    self.history.pop_front();
+                    // Observations from before the oldest snapshot can't be replayed
+                    let expired = self
+                        .history
+                        .front()
+                        .map_or(self.applied.len(), |x| x.applied);
+                    self.applied.drain(..expired);
+                    for snapshot in self.history.iter_mut() {
+                        snapshot.applied -= expired;
+                    }
+                }
+                self.history.push_back(Snapshot {
+                    time: self.time,
+                    filter: self.filter.clone(),
+                    recorded: self.recorded,
+                    applied: self.applied.len(),
+                });
+                self.applied.extend(observations.iter().cloned());
+            }
+        }
+
+        let dt = (time - self.time) as f32;
+        self.update_filter(&observations, dt);
+        self.time = time;
+    }
+
+    // Rolls the filter back to before `late` was taken and replays every observation
+    // since. Observations older than the history are dropped.
+    fn rewind(&mut self, late: Vec<Observation>) {
+        let time = late[0].time;
+        let index = match self.history.iter().rposition(|x| x.time <= time) {
+            Some(index) => index,
+            None => {
+                self.late_dropped += late.len();
+                return;
+            }
+        };
+        let snapshot = self.history.drain(index..).next().unwrap();
+
+        if let Some(smoother) = self.smoother.as_mut() {
+            smoother.rewind(self.recorded - snapshot.recorded);
+        }
+        self.filter = snapshot.filter;
+        self.time = snapshot.time;
+        self.recorded = snapshot.recorded;
+
+        let mut replay = self.applied.split_off(snapshot.applied);
+        replay.extend(late);
+
+        for group in time_groups(replay) {
+            self.step(group);
+        }
+    }
+
+    // Propagates the particles by `dt` seconds then weights them by every observation
+    // taken since the last update, multiplying their likelihoods. Observations where
+    // the animal wasn't heard weight particles by the chance of missing them.
+    pub fn update_filter(&mut self, observations: &[Observation], dt: f32) {
+        if observations.is_empty() {
+            self.predict(dt);
+            return;
+        }
+
+        let detection = self.detection;
+        let likelihood = |value: &KinematicState| {
+            observations
+                .iter()
+                .map(|observation| detection.log_observation(observation, value.position))
+                .sum::<f32>()
+        };
+        let motion = self.motion;
+        if self.lost && self.annulus_fraction > 0.0 {
+            let heard = observations.iter().find(|x| x.measurement().is_some());
+            if let Some(observation) = heard {
+                self.inject_measured(observation, self.annulus_fraction);
+            }
+        }
+
+        match self.mode {
+            FilterMode::Bootstrap => {
+                self.filter
+                    .propagate(|particle, rng| motion.propagate(particle, dt, rng));
+                self.filter.reweight(likelihood);
+                let normalized = self.filter.normalize_weights();
+                // No particle can explain the measurement, so the tracker is lost
+                if normalized.is_err() {
+                    self.reset();
+                } else {
+                    self.check_lost();
+                }
+                self.record(dt);
+                // Estimated before resampling makes every weight equal
+                self.update_estimate();
+                if normalized.is_ok() && self.filter.resample_if_needed() {
+                    if let Some(mcmc) = self.mcmc {
+                        self.filter.move_step(&mcmc, |particle, previous| {
+                            let prior =
+                                previous.map_or(0.0, |x| motion.log_density(x, particle, dt));
+                            likelihood(particle) + prior
+                        });
+                    }
+                }
+            }
+            FilterMode::Auxiliary => {
+                // First stage weights come from the noise free prediction
+                let predicted = |x: &KinematicState| likelihood(&motion.predict(x, dt));
+                let corrected = match self.filter.auxiliary_preweight(predicted) {
+                    Ok(_) => {
+                        self.filter
+                            .propagate(|particle, rng| motion.propagate(particle, dt, rng));
+                        self.filter.auxiliary_correct(likelihood)
+                    }
+                    Err(err) => Err(err),
+                };
+                if corrected.is_err() {
+                    self.reset();
+                } else {
+                    self.check_lost();
+                }
+                self.record(dt);
+                self.update_estimate();
+            }
+        }
+    }
+
+    // Moves the particles forward by `dt` seconds without weighting them
+    fn predict(&mut self, dt: f32) {
+        let motion = self.motion;
+        self.filter
+            .propagate(|particle, rng| motion.propagate(particle, dt, rng));
+        self.record(dt);
+        self.update_estimate();
+    }
+
+    // Log likelihood of `observation` under the particles predicted to its time, used
+    // to decide which tracker a detection belongs to
+    pub fn log_predictive(&self, observation: &Observation) -> f32 {
+        let (motion, detection) = (self.motion, self.detection);
+        let dt = (observation.time - self.time).max(0.0) as f32;
+        let log_likelihood = |particle: &KinematicState| {
+            let predicted = motion.predict(particle, dt);
+            detection.log_observation(observation, predicted.position)
+        };
+
+        let particles = &self.filter.particles;
+        let log_weights = &self.filter.log_weights;
+        if log_weights.len() == particles.len() {
+            let weighted = particles
+                .iter()
+                .zip(log_weights.iter())
+                .map(|(particle, weight)| weight + log_likelihood(particle));
+            log_sum_exp(weighted) - log_sum_exp(log_weights.iter().cloned())
+        } else {
+            log_sum_exp(particles.iter().map(log_likelihood)) - (particles.len() as f32).ln()
+        }
+    }
+
+    fn update_estimate(&mut self) {
+        self.estimate = self.filter.estimate(MODE_CELL_SIZE);
+        self.est_pos = self.estimate.mean.position;
+        self.est_vel = self.estimate.mean.velocity;
+
+        self.lineage.clear();
+        if let Some(genealogy) = self.filter.genealogy.as_ref() {
+            let best = self.filter.max_weight_index();
+            let positions = genealogy.lineage(best).into_iter().map(|x| x.position);
+            self.lineage.extend(positions);
+        }
+    }
+
+    fn record(&mut self, dt: f32) {
+        if let Some(smoother) = self.smoother.as_mut() {
+            smoother.record(&self.filter, dt);
+            self.recorded += 1;
+        }
+    }
+
+    // Positions the highest weighted particle descends from, newest first. Taken with
+    // the estimate, before resampling evens out the weights. Empty unless the filter
+    // records its genealogy.
+    pub fn lineage(&self) -> &[Vec2] {
+        &self.lineage
+    }
+
+    // Distinct ancestors the particles have one, two and more resamples back. Falling
+    // towards one shows path degeneracy. Empty unless the filter records its
+    // genealogy.
+    pub fn unique_ancestors(&self) -> Vec<usize> {
+        let particles = self.filter.particles.len();
+        match self.filter.genealogy.as_ref() {
+            Some(genealogy) => (1..genealogy.generations() + 1)
+                .map(|lag| genealogy.unique_ancestors(particles, lag))
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    // Resamples back until every particle shares one ancestor, if that happens within
+    // the recorded genealogy
+    pub fn coalescence_time(&self) -> Option<usize> {
+        let particles = self.filter.particles.len();
+        self.filter
+            .genealogy
+            .as_ref()
+            .and_then(|genealogy| genealogy.coalescence_time(particles))
+    }
+
+    // Smoothed position at each recorded step, oldest first. Empty unless smoothing
+    // is enabled.
+    pub fn smoothed_trajectory(&mut self) -> Vec<Vec2> {
+        let motion = self.motion;
+        let log_transition =
+            |from: &KinematicState, to: &KinematicState, dt: f32| motion.log_density(from, to, dt);
+
+        match self.smoother.as_ref() {
+            Some(smoother) => smoother.smoothed_positions(log_transition, &mut self.smoother_rng),
+            None => vec![],
+        }
+    }
+
+    // Redraws `fraction` of the particles where the measurement puts the animal, on the
+    // range ring for range measurements. They are weighted against a flat prior with
+    // the density of a uniform over the start box, so they aren't ruled out once the
+    // animal leaves it. The start box is where they are drawn if the model can't
+    // place the animal.
+    fn inject_measured(&mut self, observation: &Observation, fraction: f32) {
+        let (drone_pos, sensor) = (observation.drone, &observation.sensor);
+        let measurement = match observation.measurement() {
+            Some(measurement) => measurement,
+            None => return,
+        };
+        let h_range = self.h_range.clone();
+        let v_range = self.v_range.clone();
+        let log_area = ((h_range.end - h_range.start) * (v_range.end - v_range.start)).ln();
+
+        self.filter.inject(
+            fraction,
+            |rng| match sensor.sample_position(drone_pos, measurement, rng) {
+                Some((position, log_q)) => (KinematicState::at(position), log_q),
+                None => (
+                    KinematicState::sample_uniform(&h_range, &v_range, rng),
+                    -log_area,
+                ),
+            },
+            |_| -log_area,
+        );
+    }
+
+    // Marks the tracker lost when its freshly normalised weights have collapsed
+    fn check_lost(&mut self) {
+        let ess = self.filter.effective_sample_size();
+        self.lost = ess < LOST_ESS_FRACTION * self.filter.particles.len() as f32;
+    }
+
+    fn reset(&mut self) {
+        self.filter
+            .reset_uniformly(self.h_range.clone(), self.v_range.clone());
+        self.lost = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use filter::resample::ResamplePolicy;
+    use measurement::{Measurement, MeasurementKind, RangeModel};
+    use motion::RandomWalk;
+
+    fn tracker(out_of_sequence: OutOfSequence) -> AnimalTracker {
+        let motion = MotionKind::RandomWalk(RandomWalk { stddev: 0.2 });
+        let mut tracker =
+            AnimalTracker::new(200, -10.0..10.0, -10.0..10.0, motion, random::stream(5, 1));
+        tracker.out_of_sequence = out_of_sequence;
+        tracker
+    }
+
+    // A range of 4 from a drone at the origin, taken at `time`
+    fn observation(time: f64) -> Observation {
+        let sensor = MeasurementKind::Range(RangeModel {
+            stddev: 0.5,
+            likelihood: Default::default(),
+        });
+        Observation::new(time, vec2(0.0, 0.0), sensor, Some(Measurement::Range(4.0)))
+    }
+
+    fn positions(tracker: &AnimalTracker) -> Vec<Vec2> {
+        tracker
+            .filter
+            .particles
+            .iter()
+            .map(|x| x.position)
+            .collect()
+    }
+
+    #[test]
+    fn estimate_uses_the_weights_from_before_resampling() {
+        let mut tracker = tracker(OutOfSequence::Drop);
+        tracker.filter.set_policy(ResamplePolicy::Always);
+
+        tracker.update_filter(&[observation(1.0)], 1.0);
+
+        let particles = tracker.filter.particles.len() as f32;
+        assert!(tracker.estimate.effective_sample_size < 0.5 * particles);
+        // The highest weighted particle is near the range ring, not whichever was last
+        let range = length(&tracker.estimate.map.position);
+        assert!((range - 4.0).abs() < 1.0, "map at range {}", range);
+    }
+
+    #[test]
+    fn ancestry_diagnostics_cover_each_recorded_resample() {
+        let mut tracker = tracker(OutOfSequence::Drop);
+        tracker.filter.set_policy(ResamplePolicy::Always);
+        tracker.filter.set_genealogy(Some(5));
+        assert!(tracker.unique_ancestors().is_empty());
+
+        for &time in [1.0, 2.0, 3.0].iter() {
+            tracker.update_filter(&[observation(time)], 1.0);
+        }
+
+        let unique = tracker.unique_ancestors();
+        assert_eq!(unique.len(), 3);
+        assert!(unique.windows(2).all(|x| x[0] >= x[1]));
+        assert!(unique[0] <= tracker.filter.particles.len());
+        if let Some(lag) = tracker.coalescence_time() {
+            assert_eq!(unique[lag - 1], 1);
+        }
+        // Taken before the third resample, so it reaches back through the first two
+        assert_eq!(tracker.lineage().len(), 2);
+    }
+
+    #[test]
+    fn only_lost_trackers_draw_particles_on_the_ring() {
+        // The ring is well outside the start box
+        let far = |time| {
+            let mut observation = observation(time);
+            observation.drone = vec2(30.0, 0.0);
+            observation
+        };
+        let on_ring =
+            |tracker: &AnimalTracker| positions(tracker).iter().filter(|x| x.x > 20.0).count();
+
+        let mut healthy = tracker(OutOfSequence::Drop);
+        healthy.annulus_fraction = 0.5;
+        healthy.update_filter(&[far(1.0)], 1.0);
+        assert_eq!(on_ring(&healthy), 0);
+
+        let mut lost = tracker(OutOfSequence::Drop);
+        lost.annulus_fraction = 0.5;
+        lost.reset();
+        lost.update_filter(&[far(1.0)], 1.0);
+        assert!(on_ring(&lost) > 0);
+    }
+
+    #[test]
+    fn rewind_replays_late_observation_from_the_snapshot_before_it() {
+        let rewind = OutOfSequence::Rewind { history: 4 };
+        let mut in_order = tracker(rewind);
+        in_order.receive((1..5).map(|x| observation(x as f64 * 0.5)).collect(), 2.0);
+
+        let mut late = tracker(rewind);
+        for &time in [0.5, 1.0, 2.0].iter() {
+            late.receive(vec![observation(time)], time);
+        }
+        late.receive(vec![observation(1.5)], 2.0);
+
+        assert_eq!(late.late_dropped, 0);
+        assert_eq!(late.time, 2.0);
+        let times =
+            |tracker: &AnimalTracker| tracker.history.iter().map(|x| x.time).collect::<Vec<_>>();
+        assert_eq!(times(&late), vec![0.0, 0.5, 1.0, 1.5]);
+        assert_eq!(times(&late), times(&in_order));
+        // The filter's stream is restored with it, so the replay matches exactly
+        assert_eq!(positions(&late), positions(&in_order));
+    }
+
+    #[test]
+    fn rewind_drops_observations_older_than_its_history() {
+        let mut tracker = tracker(OutOfSequence::Rewind { history: 2 });
+        for &time in [1.0, 2.0, 3.0].iter() {
+            tracker.receive(vec![observation(time)], time);
+        }
+        let before = positions(&tracker);
+
+        tracker.receive(vec![observation(0.5)], 3.0);
+
+        assert_eq!(tracker.late_dropped, 1);
+        assert_eq!(tracker.time, 3.0);
+        assert_eq!(positions(&tracker), before);
+    }
+
+    #[test]
+    fn late_observations_are_dropped_by_default() {
+        assert!(matches!(OutOfSequence::default(), OutOfSequence::Drop));
+        assert!(matches!(
+            Config::default().out_of_sequence,
+            OutOfSequence::Drop
+        ));
+
+        let mut tracker = tracker(OutOfSequence::default());
+        tracker.receive(vec![observation(2.0)], 2.0);
+        tracker.receive(vec![observation(1.0), observation(1.5)], 2.0);
 
-        self.est_pos = filter.guess_position();
-        mem::replace(&mut self.filter, filter);
+        assert_eq!(tracker.late_dropped, 2);
+        assert_eq!(tracker.time, 2.0);
+        assert!(tracker.history.is_empty());
     }
 }
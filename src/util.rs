@@ -9,18 +9,18 @@ use state::config::Config;
 use serde_json;
 
 pub fn update_filter(filter: &mut Filter<Vec2>, drone: Vec2, animal: Vec2, stddev: f32) {
-    let measurement = generate_measurement(drone, animal, stddev);
-    filter.reweight(|value| weighting(drone, *value, measurement, stddev));
-    filter.normalize_weights();
-    filter.set_sampling_weights();
-    filter.resample();
+    let measurement = generate_measurement(drone, animal, stddev, &mut filter.rng);
+    filter.reweight(|value| log_weighting(drone, *value, measurement, stddev));
+    if filter.normalize_weights().is_ok() {
+        filter.resample_if_needed();
+    }
     filter.random_movement(0.5);
 }
 
-pub fn elipse_motion(elapsed: f32, time: f32, vert: f32, horiz: f32, rate: f32) -> Vec2 {
-    let x = -horiz * rate * (rate * elapsed).sin();
-    let y = vert * rate * (rate * elapsed).cos();
-    vec2(x, y) * time
+// Offset after `elapsed` seconds of orbiting an ellipse from its rightmost point
+pub fn elipse_offset(elapsed: f32, vert: f32, horiz: f32, rate: f32) -> Vec2 {
+    let (sin, cos) = (rate * elapsed).sin_cos();
+    vec2(horiz * (cos - 1.0), vert * sin)
 }
 
 pub fn update_renderer(